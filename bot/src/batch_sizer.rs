@@ -0,0 +1,67 @@
+use crate::config::Batching;
+
+// Sizes renewal batches from the steps used by previous simulations. The cost of a domain is
+// learned across cycles so batches grow or shrink to stay under the tx steps limit.
+pub struct BatchSizer {
+    config: Batching,
+    steps_per_domain: Option<f64>,
+    size_cap: Option<usize>,
+}
+
+// Weight of the latest estimation in the per domain cost moving average
+const COST_SMOOTHING: f64 = 0.3;
+
+impl BatchSizer {
+    pub fn new(config: &Batching) -> Self {
+        BatchSizer {
+            config: config.clone(),
+            steps_per_domain: None,
+            size_cap: None,
+        }
+    }
+
+    pub fn next_size(&self) -> usize {
+        let size = match self.steps_per_domain {
+            Some(cost) if cost > 0.0 => {
+                ((self.config.max_tx_steps as f64 * self.config.target_ratio) / cost) as usize
+            }
+            _ => self.config.initial_size,
+        };
+        let max_size = match self.size_cap {
+            Some(cap) => cap.min(self.config.max_size),
+            None => self.config.max_size,
+        };
+        size.clamp(self.config.min_size, max_size.max(self.config.min_size))
+    }
+
+    pub fn record_simulation(&mut self, batch_size: usize, steps: u64) {
+        if batch_size == 0 {
+            return;
+        }
+        let cost = steps as f64 / batch_size as f64;
+        self.steps_per_domain = Some(match self.steps_per_domain {
+            Some(previous) => previous * (1.0 - COST_SMOOTHING) + cost * COST_SMOOTHING,
+            None => cost,
+        });
+    }
+
+    // A batch of this size ran out of resources, we won't build batches that large for the rest
+    // of the cycle
+    pub fn record_resources_exceeded(&mut self, batch_size: usize) {
+        let cap = (batch_size / 2).max(self.config.min_size);
+        self.size_cap = Some(self.size_cap.map_or(cap, |current| current.min(cap)));
+    }
+
+    // The cap only covers the cycle in which resources were exceeded, the learned cost of a domain
+    // keeps the next batches under the limit
+    pub fn new_cycle(&mut self) {
+        self.size_cap = None;
+    }
+}
+
+// Error raised by the Cairo VM when a transaction runs out of steps
+const OUT_OF_STEPS: &str = "RunResources has no remaining steps";
+
+pub fn is_resources_error(error: &str) -> bool {
+    error.contains(OUT_OF_STEPS)
+}
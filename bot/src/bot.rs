@@ -1,48 +1,73 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use bigdecimal::{
     num_bigint::{BigInt, ToBigInt},
     BigDecimal,
 };
-use bson::doc;
+use chrono::Utc;
 use futures::stream::{self, StreamExt};
-use mongodb::options::FindOneOptions;
 use starknet::accounts::ConnectedAccount;
 use starknet::{
-    accounts::{Account, Call, SingleOwnerAccount},
-    core::types::FieldElement,
+    accounts::{Account, AccountError, Call, SingleOwnerAccount},
+    core::types::{
+        ExecuteInvocation, FeeEstimate, FieldElement, InvokeTransactionTrace, StarknetError,
+        TransactionTrace,
+    },
     macros::selector,
-    providers::{jsonrpc::HttpTransport, JsonRpcClient},
+    providers::{jsonrpc::HttpTransport, JsonRpcClient, Provider},
     signers::LocalWallet,
 };
 use starknet_id::encode;
-use std::str::FromStr;
 use tokio::time::{sleep, Duration as TokioDuration};
 
+use crate::batch_sizer::is_resources_error;
+use crate::fees::FeeDecision;
+use crate::journal;
+use crate::ledger::{Remaining, SpendingLedger};
 use crate::logger::Logger;
+use crate::metadata::{normalize_meta_hash, MetadataStats, TaxStates};
 use crate::models::TxResult;
-use crate::models::{AggregateResult, AggregateResults, DomainAggregateResult, MetadataDoc};
+use crate::models::{AggregateResult, AggregateResults, DomainAggregateResult, RenewalCandidates};
+use crate::nonce_manager::{is_duplicate_error, is_nonce_error};
 use crate::pipelines::{get_auto_renewal_altcoins_data, get_auto_renewal_data};
-use crate::starknet_utils::check_pending_transactions;
-use crate::starknetid_utils::{get_altcoin_quote, get_balances, get_renewal_price_eth};
-use crate::utils::to_hex;
+use crate::price_oracle::PriceOracle;
+use crate::priority::{priority_order, reorder, Priority};
+use crate::quote_cache::{apply_slippage, is_quote_valid};
+use crate::report::{AllowanceDiscrepancy, ExpiryDrift, RunReport};
+use crate::sales_tax::compute_tax;
+use crate::skip_reasons::{SkipReason, SkipSummary, SkippedDomain};
+use crate::starknet_utils::is_starknet_error;
+use crate::starknetid_utils::{get_balances_and_allowances, get_domains_expiry};
+use crate::tax_records;
+use crate::tokens::convert_eth_price;
+use crate::tx_tracker::{check_transactions, wait_for_finality, TxStatus};
+use crate::utils::{decode_domain, to_hex};
 use crate::utils::{from_uint256, hex_to_bigdecimal, to_uint256};
-use crate::{config::Config, models::AppState};
+use crate::{
+    config::{Config, UnknownTaxPolicy},
+    models::AppState,
+};
 
 lazy_static::lazy_static! {
     static ref RENEW_TIME: FieldElement = FieldElement::from_dec_str("365").unwrap();
 }
 
+// Nonce errors tolerated in a cycle before giving up on an auto renew contract
+const MAX_NONCE_RESYNCS: usize = 3;
+
 pub async fn get_domains_ready_for_renewal(
     config: &Config,
     state: &Arc<AppState>,
     logger: &Logger,
-) -> Result<HashMap<FieldElement, AggregateResults>> {
+) -> Result<RenewalCandidates> {
     let mut results = get_auto_renewal_data(config, state).await?;
     let results_altcoins = get_auto_renewal_altcoins_data(config, state).await?;
+    let candidates_count = results.len();
+    let altcoins_candidates_count = results_altcoins.len();
+    let unresolved_domains = journal::get_unresolved_domains(state).await?;
 
     let mut grouped_results: HashMap<FieldElement, AggregateResults> = HashMap::new();
 
@@ -56,14 +81,117 @@ pub async fn get_domains_ready_for_renewal(
                 tax_prices: vec![],
                 meta_hashes: vec![],
                 auto_renew_contracts: vec![],
+                expiries: vec![],
+                tax_states: vec![],
+                quotes_valid_until: vec![],
+                eth_prices: vec![],
+                tax_rates: vec![],
             },
         );
-        return Ok(grouped_results);
+        return Ok(RenewalCandidates {
+            grouped_results,
+            skipped: vec![],
+            candidates_count,
+            altcoins_candidates_count,
+            metadata_stats: MetadataStats::default(),
+            allowance_discrepancies: vec![],
+            expiry_drifts: vec![],
+        });
     }
 
     // merge all results together
     results.extend(results_altcoins.iter().cloned());
 
+    // Fetch renewal prices from the pricing contract, queried once per domain length. A domain
+    // that can't be priced is skipped without stopping the cycle.
+    let mut price_oracle = PriceOracle::new(config);
+    let mut renewal_prices_eth: Vec<Result<BigInt, (SkipReason, Option<String>)>> =
+        Vec::with_capacity(results.len());
+    for result in &results {
+        let valid_name = result
+            .domain
+            .strip_suffix(".stark")
+            .is_some_and(|name| encode(name).is_ok());
+        if !valid_name {
+            renewal_prices_eth.push(Err((SkipReason::InvalidDomainName, None)));
+            continue;
+        }
+        let price = match price_oracle
+            .get_renewal_price(&result.domain, *RENEW_TIME, logger)
+            .await
+        {
+            Ok(price) => Ok(price),
+            Err(e) => {
+                logger.warning(format!(
+                    "Unable to get the renewal price of {}: {}",
+                    result.domain, e
+                ));
+                Err((SkipReason::PriceUnavailable, Some(e.to_string())))
+            }
+        };
+        renewal_prices_eth.push(price);
+    }
+
+    // Cross-check the indexed expiries with the naming contract, the indexer can be stale or
+    // have followed a reorged block
+    let encoded_domains: Vec<Option<FieldElement>> = results
+        .iter()
+        .map(|result| {
+            result
+                .domain
+                .strip_suffix(".stark")
+                .and_then(|name| encode(name).ok())
+        })
+        .collect();
+    let domains: Vec<FieldElement> = encoded_domains.iter().flatten().copied().collect();
+    let mut domains_expiry = get_domains_expiry(config, &domains).await?.into_iter();
+    let mut onchain_expiries: Vec<Option<i64>> = encoded_domains
+        .iter()
+        .map(|encoded| encoded.and_then(|_| domains_expiry.next()))
+        .collect();
+    let expiry_drifts: Vec<ExpiryDrift> = results
+        .iter()
+        .zip(&onchain_expiries)
+        .filter_map(|(result, onchain_expiry)| {
+            let onchain_expiry = (*onchain_expiry)?;
+            let indexed_expiry = result.expiry.map(i64::from);
+            (indexed_expiry != Some(onchain_expiry)).then(|| ExpiryDrift {
+                domain: result.domain.clone(),
+                indexed_expiry,
+                onchain_expiry,
+            })
+        })
+        .collect();
+    if !expiry_drifts.is_empty() {
+        logger.warning(format!(
+            "Indexed expiry differs from the naming contract for {} domains: {:?}",
+            expiry_drifts.len(),
+            expiry_drifts
+                .iter()
+                .map(|drift| drift.domain.as_str())
+                .collect::<Vec<&str>>()
+        ));
+    }
+
+    // The ledger is allocated in this order so the domains closest to expiring are renewed first
+    let priorities: Vec<Priority> = results
+        .iter()
+        .zip(&renewal_prices_eth)
+        .zip(&onchain_expiries)
+        .map(|((result, price), onchain_expiry)| {
+            Priority::new(
+                &result.domain,
+                onchain_expiry.or(result.expiry.map(i64::from)),
+                price.as_ref().ok().cloned().map(BigDecimal::from),
+            )
+        })
+        .collect();
+    let order = priority_order(&priorities, config.renewals.tie_breaker);
+    reorder(&mut results, &order);
+    reorder(&mut renewal_prices_eth, &order);
+    reorder(&mut onchain_expiries, &order);
+    let window_end = Utc::now().timestamp() + config.renewals.expiry_days * 86400;
+
     // Fetch balances for all renewers
     let renewer_and_erc20: Vec<(String, String)> = results
         .iter()
@@ -85,79 +213,203 @@ pub async fn get_domains_ready_for_renewal(
         })
         .collect();
 
-    let balances = get_balances(config, renewer_and_erc20.clone()).await;
-    if balances.is_empty() {
-        logger.severe("Error while fetching balances for users".to_string());
-        return Err(anyhow!("Error while fetching balances"));
-    }
+    // ERC20 allowances are read on chain with the balances as the indexed ones can lag behind
+    let balance_queries: Vec<(String, String, FieldElement)> = renewer_and_erc20
+        .iter()
+        .zip(&results)
+        .map(|((renewer, erc20), result)| {
+            (renewer.clone(), erc20.clone(), result.auto_renew_contract)
+        })
+        .collect();
+    let balances = match get_balances_and_allowances(config, balance_queries).await {
+        Ok(balances) => balances,
+        Err(e) => {
+            logger.severe(format!("Error while fetching balances for users: {}", e));
+            return Err(e);
+        }
+    };
 
-    let dynamic_balances: Arc<Mutex<HashMap<String, HashMap<String, BigInt>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    let mut ledger = SpendingLedger::default();
+    let mut allowance_discrepancies: Vec<AllowanceDiscrepancy> = vec![];
+    let mut checked_allowances: HashSet<(String, String, FieldElement)> = HashSet::new();
     let mut balances_iter = balances.into_iter();
-    for (address, erc20) in &renewer_and_erc20 {
+    // the length of the results is checked by get_balances_and_allowances
+    let mut next_uint256 = || {
         balances_iter.next(); // we skip the first result as its value is 2 for low and high
-        let balance = from_uint256(
+        from_uint256(
             balances_iter.next().expect("Expected low not found"),
             balances_iter.next().expect("Expected high not found"),
+        )
+    };
+    for ((address, erc20), result) in renewer_and_erc20.iter().zip(&results) {
+        let balance = next_uint256();
+        let erc20_allowance = next_uint256();
+        let renewer = FieldElement::from_hex_be(address).unwrap();
+        let erc20_addr = FieldElement::from_hex_be(erc20).unwrap();
+        ledger.set_balance(renewer, erc20_addr, BigDecimal::from(balance));
+
+        let indexed_allowance = result
+            .approval_values
+            .iter()
+            .find(|data| data.erc20_addr == *erc20)
+            .and_then(|data| hex_to_bigdecimal(&data.approval_value))
+            .unwrap_or_else(|| BigDecimal::from(0));
+        if indexed_allowance != BigDecimal::from(erc20_allowance.clone())
+            && checked_allowances.insert((
+                address.clone(),
+                erc20.clone(),
+                result.auto_renew_contract,
+            ))
+        {
+            allowance_discrepancies.push(AllowanceDiscrepancy {
+                renewer: address.clone(),
+                erc20: erc20.clone(),
+                auto_renew_contract: to_hex(result.auto_renew_contract),
+                indexed: indexed_allowance.to_string(),
+                on_chain: erc20_allowance.to_string(),
+            });
+        }
+        ledger.set_erc20_allowance(
+            renewer,
+            erc20_addr,
+            result.auto_renew_contract,
+            BigDecimal::from(erc20_allowance),
         );
-        let mut outer_map = dynamic_balances.lock().unwrap();
-        let inner_map = outer_map.entry(address.clone()).or_default();
-        inner_map.insert(erc20.clone(), balance);
+    }
+    if !allowance_discrepancies.is_empty() {
+        logger.warning(format!(
+            "Indexed ERC20 allowance differs from the on chain one for {} renewers, using on chain values",
+            allowance_discrepancies.len()
+        ));
+    }
+
+    // Resolve the tax states of all meta hashes at once
+    let meta_hashes: HashSet<String> = results
+        .iter()
+        .filter_map(|result| result.meta_hash.as_deref())
+        .filter_map(normalize_meta_hash)
+        .collect();
+    let tax_states = TaxStates::fetch(state, meta_hashes).await?;
+    if !tax_states.missing().is_empty() {
+        logger.warning(format!(
+            "No metadata document found for {} meta hashes: {:?}",
+            tax_states.missing().len(),
+            tax_states.missing()
+        ));
     }
 
     // Then process the results
+    let unresolved_domains = &unresolved_domains;
+    let tax_states = &tax_states;
+    let quote_alerts: &Mutex<HashSet<String>> = &Mutex::new(HashSet::new());
+    // renew_domains keeps using the ledger when it reprices a batch
+    *state.ledger.lock().unwrap() = ledger;
+    let ledger = &state.ledger;
     let results_stream = stream::iter(results.into_iter().enumerate());
     let processed_results = results_stream
         .then(|(i, result)| {
             let renewer_and_erc20_cloned = renewer_and_erc20.clone();
-            let dynamic_balances = Arc::clone(&dynamic_balances);
+            let renewal_price_eth = renewal_prices_eth[i].clone();
+            let onchain_expiry = onchain_expiries.get(i).copied().flatten();
             async move {
                 let (address, erc20) = renewer_and_erc20_cloned.get(i).unwrap();
-                let balance = dynamic_balances
-                    .lock()
-                    .unwrap()
-                    .get(address)
-                    .and_then(|erc20_balances| erc20_balances.get(erc20))
-                    .cloned()
-                    .expect("Balance not found for this erc20");
-                let renewal_price_eth = get_renewal_price_eth(result.domain.clone());
+                let renewer = FieldElement::from_hex_be(address).unwrap();
+                let erc20_addr = FieldElement::from_hex_be(erc20).unwrap();
+                let skipped = |reason: SkipReason| SkippedDomain {
+                    domain: result.domain.clone(),
+                    renewer: result.renewer_address.clone(),
+                    auto_renew_contract: to_hex(result.auto_renew_contract),
+                    erc20: erc20.clone(),
+                    reason,
+                    details: None,
+                };
+                let renewal_price_eth = match renewal_price_eth {
+                    Ok(price) => price,
+                    Err((reason, details)) => {
+                        return Err(SkippedDomain {
+                            details,
+                            ..skipped(reason)
+                        })
+                    }
+                };
+                if unresolved_domains.contains(&result.domain) {
+                    return Err(skipped(SkipReason::AlreadySubmitted));
+                }
+                if let Some(onchain_expiry) = onchain_expiry.filter(|expiry| *expiry >= window_end) {
+                    return Err(SkippedDomain {
+                        details: Some(format!("expires at {}", onchain_expiry)),
+                        ..skipped(SkipReason::ExpiryOutsideWindow)
+                    });
+                }
+                if let Some(allowance) = result.allowance.as_deref().and_then(hex_to_bigdecimal) {
+                    ledger.lock().unwrap().init_renewal_allowance(
+                        renewer,
+                        erc20_addr,
+                        result.auto_renew_contract,
+                        allowance,
+                    );
+                }
+                let remaining = ledger.lock().unwrap().remaining(
+                    renewer,
+                    erc20_addr,
+                    result.auto_renew_contract,
+                );
+                let mut quote_valid_until = None;
                 let renewal_price =
-                    if FieldElement::from_hex_be(erc20).unwrap() == config.contract.erc20 {
-                        renewal_price_eth
+                    if erc20_addr == config.contract.erc20 {
+                        renewal_price_eth.clone()
                     } else {
-                        match get_altcoin_quote(config, erc20.to_string()).await {
+                        // quotes are fetched once per token and reused until they expire
+                        match state.quote_cache.get_quote(config, erc20).await {
                             Ok(quote) => {
-                                (quote * renewal_price_eth)
-                                    / BigInt::from_str("1000000000000000000").unwrap()
+                                quote_valid_until = Some(quote.valid_until);
+                                let decimals = state
+                                    .tokens
+                                    .get(&erc20_addr)
+                                    .map(|token| token.decimals)
+                                    .unwrap_or(18);
+                                convert_eth_price(&renewal_price_eth, &quote.quote, decimals)
                             }
                             Err(e) => {
-                                // in case get_altcoin_quote endpoint returns an error we panic with the error
-                                logger.severe(format!(
-                                    "Error while fetching quote on starknetid server: {:?}",
-                                    e
-                                ));
-                                panic!("Error while fetching quote on starknetid server: {:?}", e)
+                                // only the domains renewed with this token are skipped
+                                if quote_alerts.lock().unwrap().insert(erc20.clone()) {
+                                    logger.severe(format!(
+                                        "Error while fetching quote of {} on starknetid server, skipping its domains: {:?}",
+                                        erc20, e
+                                    ));
+                                }
+                                return Err(SkippedDomain {
+                                    details: Some(e.to_string()),
+                                    ..skipped(SkipReason::QuoteUnavailable)
+                                });
                             }
                         }
                     };
                 let output = process_aggregate_result(
+                    config,
                     state,
                     result.clone(),
-                    logger,
-                    BigDecimal::from(balance.to_owned()),
+                    remaining,
                     BigDecimal::from(renewal_price.to_owned()),
-                    erc20.clone(),
+                    BigDecimal::from(renewal_price_eth),
+                    tax_states,
                 )
-                .await;
+                .await
+                .map(|mut res| {
+                    res.expiry = onchain_expiry.or(res.expiry);
+                    res.quote_valid_until = quote_valid_until;
+                    res
+                })
+                .map_err(skipped);
 
-                if output.is_some() {
-                    let new_balance = balance - renewal_price;
-                    dynamic_balances
-                        .lock()
-                        .unwrap()
-                        .entry(erc20.to_string())
-                        .or_default()
-                        .insert(address.to_owned(), new_balance);
+                // following domains of the renewer can only use what is left
+                if let Ok(res) = &output {
+                    ledger.lock().unwrap().spend(
+                        renewer,
+                        erc20_addr,
+                        result.auto_renew_contract,
+                        &(res.domain_price.clone() + res.tax_price.clone()),
+                    );
                 };
 
                 output
@@ -166,262 +418,838 @@ pub async fn get_domains_ready_for_renewal(
         .collect::<Vec<_>>()
         .await;
 
-    let mut none_count = 0;
-    for res_option in processed_results.into_iter() {
-        if let Some(res) = res_option {
-            // Fetch or initialize the AggregateResults for this key
-            let entry = grouped_results
-                .entry(res.auto_renew_contract)
-                .or_insert_with(|| AggregateResults {
-                    domains: vec![],
-                    renewers: vec![],
-                    domain_prices: vec![],
-                    tax_prices: vec![],
-                    meta_hashes: vec![],
-                    auto_renew_contracts: vec![],
-                });
+    let mut skipped = vec![];
+    for processed in processed_results.into_iter() {
+        match processed {
+            Ok(res) => {
+                // Fetch or initialize the AggregateResults for this key
+                let entry = grouped_results
+                    .entry(res.auto_renew_contract)
+                    .or_insert_with(|| AggregateResults {
+                        domains: vec![],
+                        renewers: vec![],
+                        domain_prices: vec![],
+                        tax_prices: vec![],
+                        meta_hashes: vec![],
+                        auto_renew_contracts: vec![],
+                        expiries: vec![],
+                        tax_states: vec![],
+                        quotes_valid_until: vec![],
+                        eth_prices: vec![],
+                        tax_rates: vec![],
+                    });
 
-            // Append the current result to the vectors in AggregateResults
-            entry.domains.push(res.domain);
-            entry.renewers.push(res.renewer_addr);
-            entry.domain_prices.push(res.domain_price);
-            entry.tax_prices.push(res.tax_price);
-            entry.meta_hashes.push(res.meta_hash);
-        } else {
-            // Increment none_count if the result is None
-            none_count += 1;
+                // Append the current result to the vectors in AggregateResults
+                entry.domains.push(res.domain);
+                entry.renewers.push(res.renewer_addr);
+                entry.domain_prices.push(res.domain_price);
+                entry.tax_prices.push(res.tax_price);
+                entry.meta_hashes.push(res.meta_hash);
+                entry.expiries.push(res.expiry);
+                entry.tax_states.push(res.tax_state);
+                entry.quotes_valid_until.push(res.quote_valid_until);
+                entry.eth_prices.push(res.eth_price);
+                entry.tax_rates.push(res.tax_rate);
+            }
+            Err(skipped_domain) => skipped.push(skipped_domain),
         }
     }
-    logger.warning(format!("Domains that couldn't be renewed: {}", none_count));
+    logger.warning(SkipSummary::new(&skipped).to_message());
+    let mut metadata_stats = tax_states.stats();
+    metadata_stats.unknown_tax_states =
+        tax_states.unknown_tax_states(&state.states.read().unwrap());
+    if !metadata_stats.unknown_tax_states.is_empty() {
+        logger.warning(format!(
+            "Unknown tax states seen in this cycle: {}",
+            metadata_stats
+                .unknown_tax_states
+                .iter()
+                .map(|unknown| format!("{} ({} meta hashes)", unknown.code, unknown.meta_hashes))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    logger.info(format!(
+        "Metadata lookups: {} meta hashes, {} documents, {} hits, {} misses",
+        metadata_stats.meta_hashes,
+        metadata_stats.documents,
+        metadata_stats.hits,
+        metadata_stats.misses
+    ));
 
-    Ok(grouped_results)
+    Ok(RenewalCandidates {
+        grouped_results,
+        skipped,
+        candidates_count,
+        altcoins_candidates_count,
+        metadata_stats,
+        allowance_discrepancies,
+        expiry_drifts,
+    })
 }
 
 async fn process_aggregate_result(
+    config: &Config,
     state: &Arc<AppState>,
     result: DomainAggregateResult,
-    _logger: &Logger,
-    balance: BigDecimal,
+    remaining: Option<Remaining>,
     renewal_price: BigDecimal,
-    erc20_addr: String,
-) -> Option<AggregateResult> {
+    renewal_price_eth: BigDecimal,
+    tax_states: &TaxStates,
+) -> Result<AggregateResult, SkipReason> {
     // Skip the rest if auto-renewal is not enabled
-    if !result.enabled || result.allowance.is_none() {
-        return None;
+    if !result.enabled {
+        return Err(SkipReason::Disabled);
     }
+    // the domain may have been renewed since the indexer processed its expiry
+    let min_renewal_interval = config
+        .renewals
+        .min_renewal_interval
+        .unwrap_or(config.renewals.expiry_days * 86400);
+    if let Some(last_renewal) = result.last_renewal {
+        if Utc::now().timestamp() - last_renewal < min_renewal_interval {
+            return Err(SkipReason::RecentlyRenewed);
+        }
+    }
+    let remaining = remaining.ok_or(SkipReason::MissingAllowance)?;
 
     let renewer_addr = FieldElement::from_hex_be(&result.renewer_address).unwrap();
-    // map the vec of approval_values to get the approval_value for the erc20_addr selected
-    let erc20_allowance = if let Some(approval_value) = result
-        .approval_values
-        .iter()
-        .find(|&data| data.erc20_addr == erc20_addr)
-        .map(|data| data.approval_value.clone())
-    {
-        hex_to_bigdecimal(&approval_value).unwrap()
-    } else {
-        BigDecimal::from(0)
-    };
-    let allowance = hex_to_bigdecimal(&result.allowance.unwrap()).unwrap();
 
     // Check user meta hash
     let mut tax_price = BigDecimal::from(0);
+    let mut tax_rate_used = BigDecimal::from(0);
     let mut meta_hash = FieldElement::ZERO;
+    let mut tax_state = None;
     if let Some(hash) = result.meta_hash {
         meta_hash = FieldElement::from_hex_be(&hash).unwrap();
-        if hash != "0" {
-            let decimal_meta_hash =
-                BigInt::parse_bytes(hash.trim_start_matches("0x").as_bytes(), 16).unwrap();
-            let hex_meta_hash = decimal_meta_hash.to_str_radix(16);
-            let metadata_collection = state.db_metadata.collection::<MetadataDoc>("metadata");
-            if let Ok(Some(document)) = metadata_collection
-                .find_one(doc! {"meta_hash": hex_meta_hash}, FindOneOptions::default())
-                .await
-            {
-                let tax_state = document.tax_state;
-                if let Some(state_info) = state.states.states.get(&tax_state) {
-                    let tax_rate = (state_info.rate * 100.0).round() as i32;
-                    tax_price = (renewal_price.clone() * BigDecimal::from(tax_rate))
-                        / BigDecimal::from(100);
+        if let Some(hex_meta_hash) = normalize_meta_hash(&hash) {
+            tax_state = tax_states.get(&hex_meta_hash).map(str::to_owned);
+            let tax_rate = tax_state.as_ref().and_then(|tax_state| {
+                state
+                    .states
+                    .read()
+                    .unwrap()
+                    .states
+                    .get(tax_state)
+                    .map(|state_info| state_info.rate.clone())
+            });
+            // no metadata document or a tax state missing from the sales tax table
+            match (tax_rate, config.sales_tax.unknown_tax_policy) {
+                (Some(tax_rate), _) => {
+                    tax_price = compute_tax(&renewal_price, &tax_rate);
+                    tax_rate_used = tax_rate;
+                }
+                (None, UnknownTaxPolicy::Lenient) => {}
+                (None, UnknownTaxPolicy::Skip) => return Err(SkipReason::UnknownTaxState),
+                (None, UnknownTaxPolicy::DefaultRate) => {
+                    let default_rate = state.default_tax_rate.read().unwrap().clone();
+                    tax_price = compute_tax(&renewal_price, &default_rate);
+                    tax_rate_used = default_rate;
                 }
             }
         }
     }
     let final_price = renewal_price.clone() + tax_price.clone();
 
-    // Check user ERC20 allowance is greater or equal than final price = renew_price + tax_price
-    if erc20_allowance >= final_price {
-        // check user allowance is greater or equal than final price
-        if allowance >= final_price {
-            // check user balance is sufficiant
-            if balance < final_price {
-                // println!(
-                //     "Domain {} cannot be renewed because {} has not enough balance ({}) for domain price({})",
-                //     result.domain, result.renewer_address, balance, final_price
-                // );
-                return None;
-            }
+    // Check the ERC20 allowance, the renewal allowance and the balance left to the user cover
+    // final price = renew_price + tax_price
+    remaining.check(&final_price)?;
 
-            // encode domain name
-            let domain_name = result
-                .domain
-                .strip_suffix(".stark")
-                .ok_or_else(|| anyhow::anyhow!("Invalid domain name: {:?}", result.domain))
-                .unwrap();
-            let domain_encoded = encode(domain_name)
-                .map_err(|_| anyhow!("Failed to encode domain name"))
-                .context("Error occurred while encoding domain name")
-                .unwrap();
-            println!(
-                "[OK] Domain {}.stark can be renewed by {}",
-                domain_name, to_hex(renewer_addr)
-            );
-            Some(AggregateResult {
-                domain: domain_encoded,
-                renewer_addr,
-                domain_price: renewal_price,
-                tax_price,
-                meta_hash,
-                auto_renew_contract: result.auto_renew_contract,
-            })
-        } else {
-            // println!(
-            //     "Domain {} cannot be renewed because {} has set an allowance({}) lower than final price({})",
-            //     result.domain, result.renewer_address, allowance, final_price
-            // );
-            None
-        }
-    } else {
-        // println!(
-        //     "Domain {} cannot be renewed because {} has set an erc20_allowance ({}) lower than domain price({}) + tax({})",
-        //     result.domain, result.renewer_address, erc20_allowance, renewal_price, tax_price
-        // );
-        None
-    }
+    // encode domain name
+    let domain_name = result
+        .domain
+        .strip_suffix(".stark")
+        .ok_or(SkipReason::InvalidDomainName)?;
+    let domain_encoded = encode(domain_name).map_err(|_| SkipReason::InvalidDomainName)?;
+    println!(
+        "[OK] Domain {}.stark can be renewed by {}",
+        domain_name,
+        to_hex(renewer_addr)
+    );
+    Ok(AggregateResult {
+        domain: domain_encoded,
+        renewer_addr,
+        domain_price: renewal_price,
+        tax_price,
+        meta_hash,
+        auto_renew_contract: result.auto_renew_contract,
+        expiry: result.expiry.map(i64::from),
+        tax_state,
+        quote_valid_until: None,
+        eth_price: renewal_price_eth,
+        tax_rate: tax_rate_used,
+    })
 }
 
 pub async fn renew_domains(
     config: &Config,
+    state: &Arc<AppState>,
     account: &SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>,
     mut aggregate_results: AggregateResults,
     auto_renew_contract: &FieldElement,
     logger: &Logger,
+    report: &mut RunReport,
 ) -> Result<()> {
     logger.info(format!(
         "Renewing {} domains on autorenewal contract {}",
         aggregate_results.domains.len(),
         auto_renew_contract
     ));
-    let mut nonce = account.get_nonce().await.unwrap();
+    aggregate_results.sort_by_priority(config.renewals.tie_breaker);
+
+    // Re-read the expiries right before batching, a domain whose expiry moved forward since it
+    // was indexed has already been renewed
+    let onchain_expiries = get_domains_expiry(config, &aggregate_results.domains).await?;
+    let keep: Vec<bool> = onchain_expiries
+        .iter()
+        .zip(&aggregate_results.expiries)
+        .map(|(onchain_expiry, expiry)| match expiry {
+            Some(expiry) => *onchain_expiry <= *expiry,
+            None => true,
+        })
+        .collect();
+    let renewed = aggregate_results.split_off_where(&keep);
+    if !renewed.is_empty() {
+        logger.warning(format!(
+            "{} domains have already been renewed on chain: {:?}",
+            renewed.len(),
+            renewed
+                .domains
+                .iter()
+                .map(|domain| decode_domain(*domain))
+                .collect::<Vec<String>>()
+        ));
+        report.skip_batch(
+            *auto_renew_contract,
+            erc20_of(config, auto_renew_contract),
+            &renewed,
+            SkipReason::AlreadyRenewedOnChain,
+            None,
+        );
+    }
     let mut tx_results = Vec::<TxResult>::new();
+    let mut sent_batches: HashMap<FieldElement, AggregateResults> = HashMap::new();
+    let mut nonce_resyncs = 0;
 
-    // If we have: i32 more than 75 domains to renew we make multiple transactions to avoid hitting the 3M steps limit
-    while !aggregate_results.domains.is_empty()
-        && !aggregate_results.renewers.is_empty()
-        && !aggregate_results.domain_prices.is_empty()
-        && !aggregate_results.tax_prices.is_empty()
-        && !aggregate_results.meta_hashes.is_empty()
-    {
-        let size = aggregate_results.domains.len().min(75);
-        let domains_to_renew: Vec<FieldElement> =
-            aggregate_results.domains.drain(0..size).collect();
-        let renewers: Vec<FieldElement> = aggregate_results.renewers.drain(0..size).collect();
-        let domain_prices: Vec<BigDecimal> =
-            aggregate_results.domain_prices.drain(0..size).collect();
-        let tax_prices: Vec<BigDecimal> = aggregate_results.tax_prices.drain(0..size).collect();
-        let meta_hashes: Vec<FieldElement> = aggregate_results.meta_hashes.drain(0..size).collect();
+    // Batches are sized from the learned cost of a domain to avoid hitting the steps limit
+    // Batches rebuilt after isolating failing domains are queued and sent first
+    let mut queued_batches: VecDeque<AggregateResults> = VecDeque::new();
+    let outcome = loop {
+        let batch = match queued_batches.pop_front() {
+            Some(batch) => batch,
+            None if !aggregate_results.is_empty() => {
+                let size = state.batch_sizer.lock().unwrap().next_size();
+                aggregate_results.drain_front(size)
+            }
+            None => break Ok(()),
+        };
+
+        // The auto renew contract rejects prices computed from an expired quote, batches are
+        // simulated with a valid quote so that domains aren't excluded because of it
+        let batch =
+            match reprice_stale_batch(config, state, auto_renew_contract, batch, logger, report)
+                .await
+            {
+                Some(batch) if !batch.is_empty() => batch,
+                _ => continue,
+            };
+
+        let simulation = match simulate_batch(config, account, *auto_renew_contract, &batch).await {
+            Ok(simulation) => simulation,
+            Err(SimulationError::Provider(e)) => {
+                logger.severe(format!(
+                    "Unable to simulate batch of {} domains: {}",
+                    batch.len(),
+                    e
+                ));
+                break Err(e);
+            }
+            Err(SimulationError::Execution(e)) => {
+                logger.info(format!(
+                    "Error while simulating batch : {:?} for domains: {:?}",
+                    e, batch.domains
+                ));
+                if is_resources_error(&e) {
+                    state
+                        .batch_sizer
+                        .lock()
+                        .unwrap()
+                        .record_resources_exceeded(batch.len());
+                }
+                // Split the batch to find the domains making the simulation fail, the others
+                // are sent in the next transactions
+                let valid_batches = match isolate_failing_domains(
+                    config,
+                    account,
+                    *auto_renew_contract,
+                    batch,
+                    e,
+                    logger,
+                    report,
+                )
+                .await
+                {
+                    Ok(valid_batches) => valid_batches,
+                    Err(e) => {
+                        logger.severe(format!("Unable to isolate failing domains: {}", e));
+                        break Err(e);
+                    }
+                };
+                for valid_batch in valid_batches.into_iter().rev() {
+                    queued_batches.push_front(valid_batch);
+                }
+                logger.info("Continuing with the next transaction...");
+                continue;
+            }
+        };
+        state
+            .batch_sizer
+            .lock()
+            .unwrap()
+            .record_simulation(batch.len(), simulation.steps);
+        let fee_estimate = simulation.fee_estimate;
+
+        let earliest_expiry = batch.expiries.iter().flatten().min().copied();
+        let decision = state
+            .fee_policy
+            .lock()
+            .unwrap()
+            .decide(&fee_estimate, earliest_expiry);
+        let max_fee = match decision {
+            FeeDecision::Send { max_fee } => max_fee,
+            FeeDecision::Defer { gas_price } => {
+                logger.warning(format!(
+                    "Gas price {} is above the configured ceiling, deferring renewal of {} domains",
+                    gas_price,
+                    batch.len()
+                ));
+                report.skip_batch(
+                    *auto_renew_contract,
+                    erc20_of(config, auto_renew_contract),
+                    &batch,
+                    SkipReason::GasPriceTooHigh,
+                    Some(format!("gas price: {}", gas_price)),
+                );
+                continue;
+            }
+            FeeDecision::BudgetExceeded { spent } => {
+                logger.severe(format!(
+                    "Daily fee budget exceeded ({} wei spent), unable to renew {} domains",
+                    spent,
+                    batch.len()
+                ));
+                report.skip_batch(
+                    *auto_renew_contract,
+                    erc20_of(config, auto_renew_contract),
+                    &batch,
+                    SkipReason::FeeBudgetExceeded,
+                    Some(format!("spent today: {}", spent)),
+                );
+                continue;
+            }
+        };
+
+        // In dry run mode we only record the estimated batch in the report
+        if report.dry_run {
+            state
+                .fee_policy
+                .lock()
+                .unwrap()
+                .record_spent(fee_estimate.overall_fee);
+            report.add_batch(
+                *auto_renew_contract,
+                &batch,
+                None,
+                Some(fee_estimate.overall_fee),
+                None,
+            );
+            continue;
+        }
+
+        let nonce = match reserve_nonce(config, state, account, logger).await {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                logger.severe(format!(
+                    "Unable to get a nonce for the next transaction: {}",
+                    e
+                ));
+                break Err(e);
+            }
+        };
+
+        // The quote can expire while waiting for a nonce, it is checked again right before
+        // sending. The reserved nonce is only consumed once a transaction is sent.
+        let batch =
+            match reprice_stale_batch(config, state, auto_renew_contract, batch, logger, report)
+                .await
+            {
+                Some(batch) if !batch.is_empty() => batch,
+                _ => continue,
+            };
+
+        // Journal the batch before sending it so a crash can't make us lose track of it
+        let domain_names: Vec<String> = batch.domains.iter().map(|d| decode_domain(*d)).collect();
+        let pending =
+            journal::record_pending(state, nonce, *auto_renew_contract, domain_names).await;
+        let journal_id = match pending {
+            Ok(journal_id) => journal_id,
+            Err(e) => {
+                logger.severe(format!("Unable to write batch to journal: {}", e));
+                break Err(e);
+            }
+        };
 
         match send_transaction(
+            config,
             account,
             auto_renew_contract.to_owned(),
-            AggregateResults {
-                domains: domains_to_renew.clone(),
-                renewers,
-                domain_prices,
-                tax_prices,
-                meta_hashes,
-                auto_renew_contracts: vec![],
-            },
+            &batch,
             nonce,
+            max_fee,
         )
         .await
         {
             Ok(tx_hash) => {
+                state
+                    .fee_policy
+                    .lock()
+                    .unwrap()
+                    .record_spent(fee_estimate.overall_fee);
+                state.nonce_manager.lock().unwrap().sent(nonce, tx_hash);
+                if let Err(e) =
+                    journal::mark_sent(state, journal_id, tx_hash, fee_estimate.overall_fee).await
+                {
+                    logger.severe(format!(
+                        "Unable to mark tx 0x{:x} as sent in journal: {}",
+                        tx_hash, e
+                    ));
+                }
                 logger.info(format!(
                     "Sent a tx 0x{:x} to renew {:} domains with nonce: {}",
                     &tx_hash,
-                    domains_to_renew.len(),
+                    batch.len(),
                     nonce,
                 ));
+                report.add_batch(
+                    *auto_renew_contract,
+                    &batch,
+                    Some(tx_hash),
+                    Some(fee_estimate.overall_fee),
+                    None,
+                );
                 tx_results.push(TxResult {
                     tx_hash,
                     reverted: None,
                     revert_reason: None,
-                    domains_renewed: domains_to_renew.len(),
+                    domains_renewed: batch.len(),
+                    status: TxStatus::Received,
+                    sent_at: Utc::now().timestamp(),
                 });
-
-                // We only inscrease nonce if no error occurred in the previous transaction
-                nonce += FieldElement::ONE;
+                sent_batches.insert(tx_hash, batch);
             }
-            Err(e) => {
-                if e.to_string().contains("Error while estimating fee") {
-                    logger.info(format!(
-                        "Error while estimating fees : {:?} for domains: {:?}",
-                        e, domains_to_renew
+            // The transaction is already in the mempool, its batch must not be sent again
+            Err(SendError::Duplicate(e)) => {
+                logger.warning(format!(
+                    "Batch with nonce {} was already submitted, skipping it: {}",
+                    nonce, e
+                ));
+                if let Err(journal_error) = journal::mark_unknown(state, journal_id).await {
+                    logger.severe(format!(
+                        "Unable to mark batch with nonce {} as unknown in journal: {}",
+                        nonce, journal_error
                     ));
-                    logger.info("Continuing with the next transaction...");
-                    continue;
-                } else {
+                }
+                state.nonce_manager.lock().unwrap().skip(nonce);
+                report.skip_batch(
+                    *auto_renew_contract,
+                    erc20_of(config, auto_renew_contract),
+                    &batch,
+                    SkipReason::AlreadySubmitted,
+                    Some(e.to_string()),
+                );
+                continue;
+            }
+            Err(e) => {
+                if let Err(journal_error) = journal::mark_dropped(state, journal_id).await {
                     logger.severe(format!(
-                        "Error while renewing domains: {:?} for domains: {:?}",
-                        e,
-                        domains_to_renew.len()
+                        "Unable to mark batch with nonce {} as dropped in journal: {}",
+                        nonce, journal_error
                     ));
-                    return Err(e);
                 }
+                let (nonce_rejected, e) = match e {
+                    SendError::InvalidNonce(e) => (true, e),
+                    SendError::Other(e) | SendError::Duplicate(e) => (false, e),
+                };
+                // The nonce was used outside of the bot or a transaction was dropped, the batch
+                // is retried with a nonce read from the chain
+                if nonce_rejected && nonce_resyncs < MAX_NONCE_RESYNCS {
+                    nonce_resyncs += 1;
+                    logger.warning(format!(
+                        "Nonce {} was rejected, resyncing from chain: {}",
+                        nonce, e
+                    ));
+                    state.nonce_manager.lock().unwrap().invalidate();
+                    queued_batches.push_front(batch);
+                    continue;
+                }
+                logger.severe(format!(
+                    "Error while renewing domains: {:?} for domains: {:?}",
+                    e,
+                    batch.len()
+                ));
+                break Err(e);
             }
         }
 
-        check_pending_transactions(config, &mut tx_results).await;
-
-        let filtered_results: Vec<&TxResult> = tx_results
-            .iter()
-            .filter(|tx| tx.reverted.is_some())
-            .collect();
-
-        let failed_count = filtered_results
+        // Receipts are polled without waiting so that transactions stay pipelined, the process
+        // only stops once the last 3 transactions are final and reverted
+        check_transactions(config, &mut tx_results).await;
+        let failed_count = tx_results
             .iter()
             .rev()
             .take(3)
-            .filter(|tx| tx.reverted == Some(true))
+            .filter(|tx| tx.is_final(config.transactions.wait_for_l1) && tx.reverted == Some(true))
             .count();
 
         // If 3 transactions have failed, we stop the process
         if failed_count == 3 {
             logger.severe("The last 3 transactions have failed. Stopping process.");
             logger.info(format!("Sent {:?} transactions", tx_results.len()));
-            filtered_results.iter().rev().take(3).for_each(|failure| {
+            tx_results.iter().rev().take(3).for_each(|failure| {
                 logger.severe(format!(
                     "Transaction 0x{:x} with {:?} domains has failed with reason: {:?}",
                     failure.tx_hash, failure.domains_renewed, failure.revert_reason
                 ));
             });
             logger.severe("Stopping process.");
-            break;
+            break Ok(());
+        }
+    };
+
+    // Every exit path waits for the sent transactions to be final and verified so that taxes are
+    // only recorded for renewals that happened on chain
+    wait_for_finality(config, &mut tx_results, logger).await;
+    report.update_transactions(&tx_results);
+    verify_renewals(
+        config,
+        auto_renew_contract,
+        &tx_results,
+        &mut sent_batches,
+        logger,
+        report,
+    )
+    .await;
+    save_tx_outcomes(
+        config,
+        state,
+        auto_renew_contract,
+        &tx_results,
+        &sent_batches,
+        logger,
+    )
+    .await;
+    outcome
+}
+
+// Reprices a batch whose quote expired with a fresh quote of its token. Domains the renewer can
+// no longer afford at the new price are removed from the batch, the whole batch is skipped only
+// if the quote can't be refreshed.
+async fn reprice_stale_batch(
+    config: &Config,
+    state: &AppState,
+    auto_renew_contract: &FieldElement,
+    mut batch: AggregateResults,
+    logger: &Logger,
+    report: &mut RunReport,
+) -> Option<AggregateResults> {
+    let valid_until = match batch.quotes_valid_until.iter().flatten().min() {
+        Some(valid_until) if !is_quote_valid(*valid_until) => *valid_until,
+        _ => return Some(batch),
+    };
+    let erc20 = erc20_of(config, auto_renew_contract);
+    let quote = match state.quote_cache.get_quote(config, &erc20).await {
+        Ok(quote) => quote,
+        Err(e) => {
+            logger.severe(format!(
+                "Quote of {} expired at {} and can't be refreshed, not sending {} domains: {}",
+                erc20,
+                valid_until,
+                batch.len(),
+                e
+            ));
+            report.skip_batch(
+                *auto_renew_contract,
+                erc20,
+                &batch,
+                SkipReason::StaleQuote,
+                Some(format!("quote valid until: {}: {}", valid_until, e)),
+            );
+            return None;
         }
+    };
+    logger.info(format!(
+        "Quote used to price {} domains expired at {}, repricing them with a quote valid until {}",
+        batch.len(),
+        valid_until,
+        quote.valid_until
+    ));
 
-        println!("Waiting for 1 minute before sending the next transaction...");
-        sleep(TokioDuration::from_secs(60)).await;
+    let erc20_addr = FieldElement::from_hex_be(&erc20).unwrap();
+    let decimals = state
+        .tokens
+        .get(&erc20_addr)
+        .map(|token| token.decimals)
+        .unwrap_or(18);
+    let mut keep = Vec::with_capacity(batch.len());
+    let mut reasons = vec![];
+    {
+        let mut ledger = state.ledger.lock().unwrap();
+        for i in 0..batch.len() {
+            let renewer = batch.renewers[i];
+            let price = BigDecimal::from(convert_eth_price(
+                &batch.eth_prices[i].to_bigint().unwrap(),
+                &quote.quote,
+                decimals,
+            ));
+            let tax_price = compute_tax(&price, &batch.tax_rates[i]);
+            let old_amount = &batch.domain_prices[i] + &batch.tax_prices[i];
+            let new_amount = &price + &tax_price;
+            ledger.refund(renewer, erc20_addr, *auto_renew_contract, &old_amount);
+            let affordable = match ledger.remaining(renewer, erc20_addr, *auto_renew_contract) {
+                Some(remaining) => remaining.check(&new_amount),
+                None => Err(SkipReason::MissingAllowance),
+            };
+            match affordable {
+                Ok(()) => {
+                    ledger.spend(renewer, erc20_addr, *auto_renew_contract, &new_amount);
+                    batch.domain_prices[i] = price;
+                    batch.tax_prices[i] = tax_price;
+                    batch.quotes_valid_until[i] = Some(quote.valid_until);
+                    keep.push(true);
+                }
+                Err(reason) => {
+                    reasons.push(reason);
+                    keep.push(false);
+                }
+            }
+        }
+    }
+    let mut dropped = batch.split_off_where(&keep);
+    for reason in reasons {
+        report.skip_batch(
+            *auto_renew_contract,
+            erc20.clone(),
+            &dropped.drain_front(1),
+            reason,
+            Some(format!("quote valid until: {}", quote.valid_until)),
+        );
     }
-    Ok(())
+    Some(batch)
 }
 
-pub async fn send_transaction(
+// Waits until the nonce manager accepts another transaction and returns the nonce to use.
+// Transactions not included after stuck_after_blocks blocks make the manager resync from chain.
+async fn reserve_nonce(
+    config: &Config,
+    state: &AppState,
     account: &SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>,
-    auto_renew_contract: FieldElement,
-    aggregate_results: AggregateResults,
-    nonce: FieldElement,
+    logger: &Logger,
 ) -> Result<FieldElement> {
+    loop {
+        let chain_nonce = account.get_nonce().await?;
+        let current_block = account.provider().block_number().await?;
+        {
+            let mut nonce_manager = state.nonce_manager.lock().unwrap();
+            nonce_manager.update(chain_nonce, current_block);
+            let stuck = nonce_manager.stuck_transactions();
+            if !stuck.is_empty() {
+                for tx in &stuck {
+                    logger.warning(format!(
+                        "Transaction 0x{:x} with nonce {} is not included after {} blocks",
+                        tx.tx_hash,
+                        tx.nonce,
+                        current_block - tx.sent_at_block
+                    ));
+                }
+                nonce_manager.resync(chain_nonce);
+            }
+            if nonce_manager.has_capacity() {
+                if let Some(nonce) = nonce_manager.next_nonce() {
+                    return Ok(nonce);
+                }
+            }
+        }
+        println!("Waiting for pending transactions to be included...");
+        sleep(TokioDuration::from_secs(config.transactions.poll_interval)).await;
+    }
+}
+
+// Recursively halves a batch whose simulation failed until the failing domains are isolated.
+// Failing domains are reported as skipped and the batches that can be estimated are returned.
+// A provider error stops the bisection as it says nothing about the domains.
+async fn isolate_failing_domains(
+    config: &Config,
+    account: &SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>,
+    auto_renew_contract: FieldElement,
+    mut batch: AggregateResults,
+    error: String,
+    logger: &Logger,
+    report: &mut RunReport,
+) -> Result<Vec<AggregateResults>> {
+    let mut valid_batches = vec![];
+    let mut failing: Vec<(AggregateResults, String)> = vec![];
+    let mut to_check: Vec<AggregateResults> = vec![];
+    if batch.len() == 1 {
+        failing.push((batch, error));
+    } else {
+        // the whole batch is already known to fail so we start with its two halves
+        let first_half = batch.drain_front(batch.len() / 2);
+        to_check.push(batch);
+        to_check.push(first_half);
+    }
+
+    while let Some(mut batch) = to_check.pop() {
+        match simulate_batch(config, account, auto_renew_contract, &batch).await {
+            Ok(_) => valid_batches.push(batch),
+            Err(SimulationError::Provider(e)) => return Err(e),
+            Err(SimulationError::Execution(e)) if batch.len() == 1 => failing.push((batch, e)),
+            Err(SimulationError::Execution(_)) => {
+                let first_half = batch.drain_front(batch.len() / 2);
+                to_check.push(batch);
+                to_check.push(first_half);
+            }
+        }
+    }
+
+    for (failing_batch, reason) in failing {
+        logger.warning(format!(
+            "Domain {} excluded from renewal, simulation failed: {}",
+            decode_domain(failing_batch.domains[0]),
+            reason
+        ));
+        report.skip_batch(
+            auto_renew_contract,
+            erc20_of(config, &auto_renew_contract),
+            &failing_batch,
+            SkipReason::FeeEstimationFailed,
+            Some(reason),
+        );
+    }
+    Ok(valid_batches)
+}
+
+fn erc20_of(config: &Config, auto_renew_contract: &FieldElement) -> String {
+    config
+        .renewers_mapping
+        .get(auto_renew_contract)
+        .map(|erc20| to_hex(*erc20))
+        .unwrap_or_default()
+}
+
+// A successful batch_renew can still leave some domains untouched because of the checks of the
+// contract. The new expiry of every domain is read back and the domains whose expiry didn't move
+// by the renewal duration are removed from their batch and reported, they are picked up again by
+// the next cycle.
+async fn verify_renewals(
+    config: &Config,
+    auto_renew_contract: &FieldElement,
+    tx_results: &[TxResult],
+    sent_batches: &mut HashMap<FieldElement, AggregateResults>,
+    logger: &Logger,
+    report: &mut RunReport,
+) {
+    let renew_duration = u64::try_from(*RENEW_TIME).unwrap() as i64 * 86400;
+    for tx_result in tx_results {
+        if tx_result.reverted != Some(false) {
+            continue;
+        }
+        let batch = match sent_batches.get_mut(&tx_result.tx_hash) {
+            Some(batch) => batch,
+            None => continue,
+        };
+        let onchain_expiries = match get_domains_expiry(config, &batch.domains).await {
+            Ok(expiries) => expiries,
+            Err(e) => {
+                logger.warning(format!(
+                    "Unable to verify the renewals of tx 0x{:x}: {}",
+                    tx_result.tx_hash, e
+                ));
+                continue;
+            }
+        };
+        let renewed: Vec<bool> = onchain_expiries
+            .iter()
+            .zip(&batch.expiries)
+            .map(|(onchain_expiry, expiry)| match expiry {
+                Some(expiry) => *onchain_expiry >= expiry + renew_duration,
+                None => *onchain_expiry > tx_result.sent_at,
+            })
+            .collect();
+        let not_renewed = batch.split_off_where(&renewed);
+        if not_renewed.is_empty() {
+            continue;
+        }
+        logger.severe(format!(
+            "{} domains of tx 0x{:x} weren't renewed: {:?}",
+            not_renewed.len(),
+            tx_result.tx_hash,
+            not_renewed
+                .domains
+                .iter()
+                .map(|domain| decode_domain(*domain))
+                .collect::<Vec<String>>()
+        ));
+        report.skip_batch(
+            *auto_renew_contract,
+            erc20_of(config, auto_renew_contract),
+            &not_renewed,
+            SkipReason::NotRenewed,
+            Some(format!("tx 0x{:x}", tx_result.tx_hash)),
+        );
+    }
+}
+
+// Persist the known outcome of the sent transactions in the journal and record the taxes
+// collected by the successful ones
+async fn save_tx_outcomes(
+    config: &Config,
+    state: &AppState,
+    auto_renew_contract: &FieldElement,
+    tx_results: &[TxResult],
+    sent_batches: &HashMap<FieldElement, AggregateResults>,
+    logger: &Logger,
+) {
+    if let Err(e) = journal::mark_final(state, tx_results).await {
+        logger.severe(format!("Unable to update journal: {}", e));
+    }
+    for tx_result in tx_results {
+        if tx_result.reverted != Some(false) {
+            continue;
+        }
+        if let Some(batch) = sent_batches.get(&tx_result.tx_hash) {
+            if let Err(e) = tax_records::record_batch(
+                state,
+                tx_result.tx_hash,
+                *auto_renew_contract,
+                erc20_of(config, auto_renew_contract),
+                batch,
+            )
+            .await
+            {
+                logger.severe(format!(
+                    "Unable to record taxes of tx 0x{:x}: {}",
+                    tx_result.tx_hash, e
+                ));
+            }
+        }
+    }
+}
+
+fn build_calldata(
+    config: &Config,
+    auto_renew_contract: &FieldElement,
+    aggregate_results: &AggregateResults,
+) -> Vec<FieldElement> {
     let mut calldata: Vec<FieldElement> = Vec::new();
     calldata
         .push(FieldElement::from_dec_str(&aggregate_results.domains.len().to_string()).unwrap());
@@ -432,7 +1260,115 @@ pub async fn send_transaction(
     calldata.push(
         FieldElement::from_dec_str(&aggregate_results.domain_prices.len().to_string()).unwrap(),
     );
+    // prices computed from a quote are sent with the slippage of their token as the contract
+    // prices the domains with its own quote
+    let slippage = config
+        .renewers_mapping
+        .get(auto_renew_contract)
+        .and_then(|erc20| config.renewer_of_token(erc20))
+        .and_then(|renewer| renewer.slippage);
+    for (domain_price, quote_valid_until) in aggregate_results
+        .domain_prices
+        .iter()
+        .zip(&aggregate_results.quotes_valid_until)
+    {
+        let limit_price = match quote_valid_until {
+            Some(_) => apply_slippage(domain_price, slippage),
+            None => domain_price.clone(),
+        };
+        let (low, high) = to_uint256(limit_price.to_bigint().unwrap());
+        calldata.push(low);
+        calldata.push(high);
+    }
 
+    calldata
+        .push(FieldElement::from_dec_str(&aggregate_results.tax_prices.len().to_string()).unwrap());
+    for tax_price in &aggregate_results.tax_prices {
+        let (low, high) = to_uint256(tax_price.to_bigint().unwrap());
+        calldata.push(low);
+        calldata.push(high);
+    }
+    calldata.push(
+        FieldElement::from_dec_str(&aggregate_results.meta_hashes.len().to_string()).unwrap(),
+    );
+    calldata.extend_from_slice(&aggregate_results.meta_hashes);
+    calldata
+}
+
+pub struct BatchSimulation {
+    pub fee_estimate: FeeEstimate,
+    pub steps: u64,
+}
+
+// Only execution errors are caused by the domains of a batch, the other errors (rate limits,
+// unreachable node, ...) are returned to the caller
+pub enum SimulationError {
+    Execution(String),
+    Provider(anyhow::Error),
+}
+
+// Simulate the batch_renew call to get its fee estimate and the steps it uses, the fee isn't
+// charged so the simulation doesn't depend on the max fee
+pub async fn simulate_batch(
+    config: &Config,
+    account: &SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>,
+    auto_renew_contract: FieldElement,
+    aggregate_results: &AggregateResults,
+) -> Result<BatchSimulation, SimulationError> {
+    let execution = account.execute(vec![Call {
+        to: auto_renew_contract,
+        selector: selector!("batch_renew"),
+        calldata: build_calldata(config, &auto_renew_contract, aggregate_results),
+    }]);
+    let simulation = match execution.simulate(false, true).await {
+        Ok(simulation) => simulation,
+        Err(AccountError::Provider(e)) if is_starknet_error(&e, StarknetError::ContractError) => {
+            return Err(SimulationError::Execution(e.to_string()))
+        }
+        Err(e) => {
+            return Err(SimulationError::Provider(anyhow!(
+                "Error while simulating transaction: {}",
+                e
+            )))
+        }
+    };
+    match simulation.transaction_trace {
+        TransactionTrace::Invoke(InvokeTransactionTrace {
+            execute_invocation: ExecuteInvocation::Success(invocation),
+            ..
+        }) => Ok(BatchSimulation {
+            fee_estimate: simulation.fee_estimation,
+            steps: invocation.execution_resources.steps,
+        }),
+        TransactionTrace::Invoke(InvokeTransactionTrace {
+            execute_invocation: ExecuteInvocation::Reverted(reverted),
+            ..
+        }) => Err(SimulationError::Execution(format!(
+            "Transaction reverted during simulation: {}",
+            reverted.revert_reason
+        ))),
+        _ => Err(SimulationError::Provider(anyhow!(
+            "Unexpected trace for an invoke transaction"
+        ))),
+    }
+}
+
+// A rejected nonce can be retried with a nonce read from the chain, a duplicate transaction is
+// already pending, other errors stop the cycle
+pub enum SendError {
+    InvalidNonce(anyhow::Error),
+    Duplicate(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+pub async fn send_transaction(
+    config: &Config,
+    account: &SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>,
+    auto_renew_contract: FieldElement,
+    aggregate_results: &AggregateResults,
+    nonce: FieldElement,
+    max_fee: u64,
+) -> Result<FieldElement, SendError> {
     println!("domains:");
     for x in &aggregate_results.domains {
         println!("{}", x);
@@ -458,50 +1394,27 @@ pub async fn send_transaction(
         println!("{}", x);
     }
 
-    for limit_price in &aggregate_results.domain_prices {
-        let (low, high) = to_uint256(limit_price.to_bigint().unwrap());
-        calldata.push(low);
-        calldata.push(high);
-    }
-
-    calldata
-        .push(FieldElement::from_dec_str(&aggregate_results.tax_prices.len().to_string()).unwrap());
-    for tax_price in &aggregate_results.tax_prices {
-        let (low, high) = to_uint256(tax_price.to_bigint().unwrap());
-        calldata.push(low);
-        calldata.push(high);
-    }
-    calldata.push(
-        FieldElement::from_dec_str(&aggregate_results.meta_hashes.len().to_string()).unwrap(),
-    );
-    calldata.extend_from_slice(&aggregate_results.meta_hashes);
-
-    let execution = account
+    match account
         .execute(vec![Call {
             to: auto_renew_contract,
             selector: selector!("batch_renew"),
-            calldata: calldata.clone(),
+            calldata: build_calldata(config, &auto_renew_contract, aggregate_results),
         }])
-        .fee_estimate_multiplier(5.0f64);
-
-    match execution.estimate_fee().await {
-        Ok(_) => match execution
-            .nonce(nonce)
-            // harcode max fee to 10$ = 0.0028 ETH
-            .max_fee(FieldElement::from(2800000000000000_u64))
-            .send()
-            .await
-        {
-            Ok(tx_result) => Ok(tx_result.transaction_hash),
-            Err(e) => {
-                let error_message = format!("An error occurred while renewing domains: {}", e);
-                Err(anyhow::anyhow!(error_message))
-            }
-        },
+        .nonce(nonce)
+        .max_fee(FieldElement::from(max_fee))
+        .send()
+        .await
+    {
+        Ok(tx_result) => Ok(tx_result.transaction_hash),
         Err(e) => {
-            println!("Error while estimating fee: {:?}", e);
-            let error_message = format!("Error while estimating fee: {}", e);
-            Err(anyhow::anyhow!(error_message))
+            let error_message = format!("An error occurred while renewing domains: {}", e);
+            if is_nonce_error(&e) {
+                Err(SendError::InvalidNonce(anyhow!(error_message)))
+            } else if is_duplicate_error(&e) {
+                Err(SendError::Duplicate(anyhow!(error_message)))
+            } else {
+                Err(SendError::Other(anyhow!(error_message)))
+            }
         }
     }
 }
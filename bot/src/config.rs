@@ -41,8 +41,128 @@ pub_struct!(Clone, Deserialize; MyAccount {
 pub_struct!(Clone, Deserialize; Renewals {
     delay: u64,
     expiry_days: i64,
+    tie_breaker: Option<TieBreaker>,
+    // seconds, defaults to expiry_days
+    min_renewal_interval: Option<i64>,
 });
 
+// Order of the domains expiring at the same time, the first ones are renewed when a renewer
+// can't afford all of them
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TieBreaker {
+    ShortestName,
+    MostExpensive,
+}
+
+pub_struct!(Clone, Deserialize, Default; Pricing {
+    fallback_to_constants: bool,
+});
+
+pub_struct!(Clone, Deserialize; Batching {
+    initial_size: usize,
+    min_size: usize,
+    max_size: usize,
+    max_tx_steps: u64,
+    target_ratio: f64,
+});
+
+impl Default for Batching {
+    fn default() -> Self {
+        Batching {
+            initial_size: 75,
+            min_size: 1,
+            max_size: 200,
+            max_tx_steps: 3_000_000,
+            target_ratio: 0.8,
+        }
+    }
+}
+
+pub_struct!(Clone, Deserialize; Fees {
+    multiplier: f64,
+    max_fee: u64,
+    daily_budget: Option<u64>,
+    max_gas_price: Option<u64>,
+    min_days_before_expiry: i64,
+});
+
+impl Default for Fees {
+    fn default() -> Self {
+        Fees {
+            multiplier: 5.0,
+            // 10$ = 0.0028 ETH
+            max_fee: 2800000000000000,
+            daily_budget: None,
+            max_gas_price: None,
+            min_days_before_expiry: 3,
+        }
+    }
+}
+
+pub_struct!(Clone, Deserialize; Transactions {
+    max_in_flight: usize,
+    stuck_after_blocks: u64,
+    poll_interval: u64,
+    max_poll_interval: u64,
+    not_found_timeout: u64,
+    finality_timeout: u64,
+    wait_for_l1: bool,
+});
+
+impl Default for Transactions {
+    fn default() -> Self {
+        Transactions {
+            max_in_flight: 3,
+            stuck_after_blocks: 20,
+            poll_interval: 10,
+            max_poll_interval: 60,
+            not_found_timeout: 300,
+            finality_timeout: 1800,
+            wait_for_l1: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SalesTaxSource {
+    File,
+    Mongodb,
+    Http,
+}
+
+// How to tax domains whose meta hash has no metadata document or an unknown tax state
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownTaxPolicy {
+    Lenient,
+    Skip,
+    DefaultRate,
+}
+
+// location is a file path, a collection of the main database or an url depending on the source
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SalesTax {
+    pub source: SalesTaxSource,
+    pub location: String,
+    pub unknown_tax_policy: UnknownTaxPolicy,
+    // only used with the default_rate policy
+    pub default_rate: f64,
+}
+
+impl Default for SalesTax {
+    fn default() -> Self {
+        SalesTax {
+            source: SalesTaxSource::File,
+            location: "./bot/src/sales_tax.json".to_string(),
+            unknown_tax_policy: UnknownTaxPolicy::Lenient,
+            default_rate: 0.0,
+        }
+    }
+}
+
 pub_struct!(Clone, Deserialize; IndexerServer {
     port: Vec<u16>,
     server_url: String,
@@ -70,9 +190,17 @@ pub_struct!(Clone, Deserialize; Server {
     starknetid_api: String,
 });
 
+// decimals and symbol are read from the token contract when not configured. Quotes are the
+// price of 1 ETH in the token, max_quote_deviation and slippage are fractions (0.1 for 10%).
 pub_struct!(Clone, Deserialize; Renewer {
     address: FieldElement,
     renewal_contract: FieldElement,
+    decimals: Option<u32>,
+    symbol: Option<String>,
+    min_quote: Option<f64>,
+    max_quote: Option<f64>,
+    max_quote_deviation: Option<f64>,
+    slippage: Option<f64>,
 });
 
 pub_struct!(Clone; Config {
@@ -80,13 +208,27 @@ pub_struct!(Clone; Config {
     database: Database,
     account: MyAccount,
     renewals: Renewals,
+    pricing: Pricing,
+    batching: Batching,
+    fees: Fees,
+    transactions: Transactions,
+    sales_tax: SalesTax,
     indexer_server: IndexerServer,
     rpc: Rpc,
     watchtower: Watchtower,
     server: Server,
+    renewers: HashMap<String, Renewer>,
     renewers_mapping: HashMap<FieldElement, FieldElement>,
 });
 
+impl Config {
+    pub fn renewer_of_token(&self, erc20: &FieldElement) -> Option<&Renewer> {
+        self.renewers
+            .values()
+            .find(|renewer| renewer.address == *erc20)
+    }
+}
+
 impl<'de> Deserialize<'de> for Config {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -98,6 +240,16 @@ impl<'de> Deserialize<'de> for Config {
             database: Database,
             account: MyAccount,
             renewals: Renewals,
+            #[serde(default)]
+            pricing: Pricing,
+            #[serde(default)]
+            batching: Batching,
+            #[serde(default)]
+            fees: Fees,
+            #[serde(default)]
+            transactions: Transactions,
+            #[serde(default)]
+            sales_tax: SalesTax,
             indexer_server: IndexerServer,
             rpc: Rpc,
             watchtower: Watchtower,
@@ -110,6 +262,11 @@ impl<'de> Deserialize<'de> for Config {
             database,
             account,
             renewals,
+            pricing,
+            batching,
+            fees,
+            transactions,
+            sales_tax,
             indexer_server,
             rpc,
             watchtower,
@@ -119,7 +276,7 @@ impl<'de> Deserialize<'de> for Config {
 
         // Build atcoins mapping
         let renewers_mapping = renewers
-            .into_values()
+            .values()
             .map(|val| (val.renewal_contract, val.address))
             .collect();
 
@@ -128,22 +285,88 @@ impl<'de> Deserialize<'de> for Config {
             database,
             account,
             renewals,
+            pricing,
+            batching,
+            fees,
+            transactions,
+            sales_tax,
             indexer_server,
             rpc,
             watchtower,
             server,
+            renewers,
             renewers_mapping,
         })
     }
 }
 
-pub fn load() -> Config {
-    let args: Vec<String> = env::args().collect();
-    let config_path = if args.len() <= 1 {
-        "config.toml"
-    } else {
-        args.get(1).unwrap()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// Export of the collected taxes per jurisdiction, dates are YYYY-MM-DD
+pub struct TaxExport {
+    pub format: ExportFormat,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub output: Option<String>,
+}
+
+pub struct Args {
+    pub config_path: String,
+    pub dry_run: bool,
+    pub report_path: String,
+    pub tax_export: Option<TaxExport>,
+}
+
+pub fn parse_args() -> Args {
+    let mut args = Args {
+        config_path: "config.toml".to_string(),
+        dry_run: false,
+        report_path: "dry_run_report.json".to_string(),
+        tax_export: None,
     };
+    let mut from = None;
+    let mut to = None;
+    let mut output = None;
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dry-run" => args.dry_run = true,
+            "--report-path" => match iter.next() {
+                Some(path) => args.report_path = path,
+                None => panic!("error: --report-path expects a file path"),
+            },
+            "--export-taxes" => {
+                let format = match iter.next().as_deref() {
+                    Some("csv") => ExportFormat::Csv,
+                    Some("json") => ExportFormat::Json,
+                    _ => panic!("error: --export-taxes expects csv or json"),
+                };
+                args.tax_export = Some(TaxExport {
+                    format,
+                    from: None,
+                    to: None,
+                    output: None,
+                });
+            }
+            "--from" => from = iter.next(),
+            "--to" => to = iter.next(),
+            "--output" => output = iter.next(),
+            _ => args.config_path = arg,
+        }
+    }
+    if let Some(tax_export) = &mut args.tax_export {
+        tax_export.from = from;
+        tax_export.to = to;
+        tax_export.output = output;
+    }
+    args
+}
+
+pub fn load(config_path: &str) -> Config {
     let file_contents = fs::read_to_string(config_path);
     if file_contents.is_err() {
         panic!("error: unable to read file with path \"{}\"", config_path);
@@ -0,0 +1,87 @@
+use anyhow::Result;
+use chrono::{NaiveDate, TimeZone, Utc};
+use starknet::core::types::FeeEstimate;
+
+use crate::{config::Fees, journal, models::AppState};
+
+pub enum FeeDecision {
+    Send { max_fee: u64 },
+    // gas price is above the configured ceiling and the batch can wait for the next cycle
+    Defer { gas_price: u64 },
+    BudgetExceeded { spent: u64 },
+}
+
+// Applies the [fees] policy to every batch and keeps track of the fees spent during the day
+pub struct FeePolicy {
+    config: Fees,
+    day: NaiveDate,
+    spent: u64,
+}
+
+impl FeePolicy {
+    pub fn new(config: &Fees) -> Self {
+        FeePolicy {
+            config: config.clone(),
+            day: Utc::now().date_naive(),
+            spent: 0,
+        }
+    }
+
+    pub fn max_fee(&self, fee_estimate: &FeeEstimate) -> u64 {
+        let max_fee = (fee_estimate.overall_fee as f64 * self.config.multiplier) as u64;
+        max_fee.min(self.config.max_fee)
+    }
+
+    // earliest_expiry is the soonest expiry timestamp of the domains in the batch
+    pub fn decide(
+        &mut self,
+        fee_estimate: &FeeEstimate,
+        earliest_expiry: Option<i64>,
+    ) -> FeeDecision {
+        let today = Utc::now().date_naive();
+        if today != self.day {
+            self.day = today;
+            self.spent = 0;
+        }
+
+        if let Some(daily_budget) = self.config.daily_budget {
+            if self.spent + fee_estimate.overall_fee > daily_budget {
+                return FeeDecision::BudgetExceeded { spent: self.spent };
+            }
+        }
+
+        if let Some(max_gas_price) = self.config.max_gas_price {
+            let deadline = Utc::now().timestamp() + self.config.min_days_before_expiry * 86400;
+            let can_wait = earliest_expiry.is_some_and(|expiry| expiry > deadline);
+            if fee_estimate.gas_price > max_gas_price && can_wait {
+                return FeeDecision::Defer {
+                    gas_price: fee_estimate.gas_price,
+                };
+            }
+        }
+
+        FeeDecision::Send {
+            max_fee: self.max_fee(fee_estimate),
+        }
+    }
+
+    pub fn record_spent(&mut self, fee: u64) {
+        self.spent += fee;
+    }
+
+    // Fees spent earlier today, before the bot was restarted
+    pub fn restore_spent(&mut self, spent: u64) {
+        self.day = Utc::now().date_naive();
+        self.spent = spent;
+    }
+}
+
+// Fees of the transactions sent today, read from the journal where they are written as soon as
+// a transaction is sent so that a restart, even in the middle of a cycle, doesn't reset the
+// daily budget
+pub async fn load_spent_today(state: &AppState) -> Result<u64> {
+    let midnight = Utc
+        .from_utc_datetime(&Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap())
+        .timestamp();
+    journal::get_fees_spent_since(state, midnight).await
+}
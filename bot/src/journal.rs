@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use bson::{doc, oid::ObjectId, Bson};
+use chrono::Utc;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use starknet::{
+    core::types::{BlockId, BlockTag, FieldElement},
+    providers::Provider,
+};
+
+use crate::{
+    config::Config,
+    logger::Logger,
+    models::{AppState, TxResult},
+    starknet_utils::create_jsonrpc_client,
+    tx_tracker::{check_transactions, TxStatus},
+    utils::to_hex,
+};
+
+// Every batch is written to the journal before being sent so that a crash between sending a
+// transaction and checking its receipt never makes us forget or resubmit its domains.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalStatus {
+    Pending,
+    Sent,
+    Confirmed,
+    Reverted,
+    Dropped,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    // entries are updated by id, a nonce can be journaled again once its batch was dropped
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub nonce: String,
+    pub tx_hash: Option<String>,
+    pub auto_renew_contract: String,
+    pub domains: Vec<String>,
+    pub status: JournalStatus,
+    pub revert_reason: Option<String>,
+    // estimated fee of the transaction, counted in the daily budget from the time it is sent
+    pub fee: Option<u64>,
+    pub sent_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+fn journal(state: &AppState) -> mongodb::Collection<JournalEntry> {
+    state.db.collection::<JournalEntry>("auto_renew_journal")
+}
+
+pub async fn record_pending(
+    state: &AppState,
+    nonce: FieldElement,
+    auto_renew_contract: FieldElement,
+    domains: Vec<String>,
+) -> Result<ObjectId> {
+    let now = Utc::now().timestamp();
+    let inserted = journal(state)
+        .insert_one(
+            JournalEntry {
+                id: None,
+                nonce: to_hex(nonce),
+                tx_hash: None,
+                auto_renew_contract: to_hex(auto_renew_contract),
+                domains,
+                status: JournalStatus::Pending,
+                revert_reason: None,
+                fee: None,
+                sent_at: None,
+                created_at: now,
+                updated_at: now,
+            },
+            None,
+        )
+        .await?;
+    inserted
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| anyhow!("Journal entry was inserted without an object id"))
+}
+
+pub async fn mark_sent(
+    state: &AppState,
+    id: ObjectId,
+    tx_hash: FieldElement,
+    fee: u64,
+) -> Result<()> {
+    let now = Utc::now().timestamp();
+    journal(state)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": {
+                "status": "sent",
+                "tx_hash": to_hex(tx_hash),
+                "fee": fee as i64,
+                "sent_at": now,
+                "updated_at": now,
+            }},
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn mark_dropped(state: &AppState, id: ObjectId) -> Result<()> {
+    journal(state)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "status": "dropped", "updated_at": Utc::now().timestamp() } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// The batch may be included on chain but its transaction hash isn't known
+pub async fn mark_unknown(state: &AppState, id: ObjectId) -> Result<()> {
+    journal(state)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "status": "unknown", "updated_at": Utc::now().timestamp() } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn mark_final(state: &AppState, tx_results: &[TxResult]) -> Result<()> {
+    for tx_result in tx_results {
+        let status = match tx_result.reverted {
+            Some(true) => "reverted",
+            Some(false) => "confirmed",
+            None => continue,
+        };
+        journal(state)
+            .update_one(
+                doc! { "tx_hash": to_hex(tx_result.tx_hash), "status": "sent" },
+                doc! { "$set": {
+                    "status": status,
+                    "revert_reason": tx_result.revert_reason.clone(),
+                    "updated_at": Utc::now().timestamp(),
+                }},
+                None,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// Resolve the entries left pending or sent by a previous run using the transaction receipts
+// and the current nonce of the bot account.
+pub async fn reconcile(config: &Config, state: &AppState, logger: &Logger) -> Result<()> {
+    let unresolved: Vec<JournalEntry> = journal(state)
+        .find(doc! { "status": { "$in": ["pending", "sent"] } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    if unresolved.is_empty() {
+        return Ok(());
+    }
+
+    let provider = create_jsonrpc_client(config);
+    let account_nonce = provider
+        .get_nonce(BlockId::Tag(BlockTag::Pending), config.account.address)
+        .await?;
+
+    let mut sent_txs: Vec<TxResult> = unresolved
+        .iter()
+        .filter_map(|entry| {
+            entry.tx_hash.as_ref().map(|tx_hash| TxResult {
+                tx_hash: FieldElement::from_hex_be(tx_hash).unwrap(),
+                reverted: None,
+                revert_reason: None,
+                domains_renewed: 0,
+                status: TxStatus::Received,
+                // entries are updated when marked as sent
+                sent_at: entry.updated_at,
+            })
+        })
+        .collect();
+    check_transactions(config, &mut sent_txs).await;
+    mark_final(state, &sent_txs).await?;
+
+    for entry in &unresolved {
+        let nonce = FieldElement::from_hex_be(&entry.nonce).unwrap();
+        let nonce_used = account_nonce > nonce;
+        let status = match &entry.tx_hash {
+            Some(tx_hash) => {
+                // Only a transaction the node confirmed it doesn't know is dropped, a failed
+                // receipt lookup leaves the entry for the next reconciliation
+                let not_found = sent_txs.iter().any(|tx| {
+                    to_hex(tx.tx_hash) == *tx_hash
+                        && tx.reverted.is_none()
+                        && tx.status == TxStatus::NotFound
+                });
+                if !not_found || !nonce_used {
+                    // resolved above, still waiting to be included or unknown for now
+                    continue;
+                }
+                // the nonce was consumed by another transaction
+                JournalStatus::Dropped
+            }
+            None if nonce_used => {
+                logger.warning(format!(
+                    "Unable to know if the batch with nonce {} was sent, renewal of {:?} will rely on indexed expiries",
+                    entry.nonce, entry.domains
+                ));
+                JournalStatus::Unknown
+            }
+            None => JournalStatus::Dropped,
+        };
+        journal(state)
+            .update_one(
+                doc! { "_id": entry.id },
+                doc! { "$set": {
+                    "status": bson::to_bson(&status)?,
+                    "updated_at": Utc::now().timestamp(),
+                }},
+                None,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// Sum of the fees of the transactions sent since the given timestamp
+pub async fn get_fees_spent_since(state: &AppState, since: i64) -> Result<u64> {
+    let pipeline = vec![
+        doc! { "$match": { "sent_at": { "$gte": since } } },
+        doc! { "$group": { "_id": Bson::Null, "spent": { "$sum": "$fee" } } },
+    ];
+    let results: Vec<bson::Document> = journal(state)
+        .clone_with_type::<bson::Document>()
+        .aggregate(pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(
+        match results.first().and_then(|result| result.get("spent")) {
+            Some(Bson::Int64(spent)) => *spent as u64,
+            Some(Bson::Int32(spent)) => *spent as u64,
+            _ => 0,
+        },
+    )
+}
+
+// Domains of batches that may still be included on chain, they must not be submitted again
+pub async fn get_unresolved_domains(state: &AppState) -> Result<HashSet<String>> {
+    let unresolved: Vec<JournalEntry> = journal(state)
+        .find(doc! { "status": { "$in": ["pending", "sent"] } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(unresolved
+        .into_iter()
+        .flat_map(|entry| entry.domains)
+        .collect())
+}
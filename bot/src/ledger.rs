@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use bigdecimal::BigDecimal;
+use starknet::core::types::FieldElement;
+
+use crate::skip_reasons::SkipReason;
+
+// What a renewer can still spend on an auto renew contract in the current cycle
+#[derive(Debug, Clone)]
+pub struct Remaining {
+    pub balance: BigDecimal,
+    pub erc20_allowance: BigDecimal,
+    pub renewal_allowance: BigDecimal,
+}
+
+impl Remaining {
+    // amount includes the tax
+    pub fn check(&self, amount: &BigDecimal) -> Result<(), SkipReason> {
+        if self.erc20_allowance < *amount {
+            return Err(SkipReason::Erc20AllowanceTooLow);
+        }
+        if self.renewal_allowance < *amount {
+            return Err(SkipReason::RenewalAllowanceTooLow);
+        }
+        if self.balance < *amount {
+            return Err(SkipReason::BalanceTooLow);
+        }
+        Ok(())
+    }
+}
+
+// Tracks the funds of each renewer through a cycle so that the domains accepted for renewal
+// never exceed what a batch can actually transfer. The balance is shared by all the auto renew
+// contracts of a token while the ERC20 approval and the renewal allowance are per contract.
+#[derive(Default)]
+pub struct SpendingLedger {
+    balances: HashMap<(FieldElement, FieldElement), BigDecimal>,
+    erc20_allowances: HashMap<(FieldElement, FieldElement, FieldElement), BigDecimal>,
+    renewal_allowances: HashMap<(FieldElement, FieldElement, FieldElement), BigDecimal>,
+}
+
+impl SpendingLedger {
+    pub fn set_balance(&mut self, renewer: FieldElement, erc20: FieldElement, balance: BigDecimal) {
+        self.balances.insert((renewer, erc20), balance);
+    }
+
+    pub fn set_erc20_allowance(
+        &mut self,
+        renewer: FieldElement,
+        erc20: FieldElement,
+        auto_renew_contract: FieldElement,
+        allowance: BigDecimal,
+    ) {
+        self.erc20_allowances
+            .insert((renewer, erc20, auto_renew_contract), allowance);
+    }
+
+    // The renewal allowance is repeated on every domain of a renewer, only the first one is kept
+    // so that spent amounts aren't reset
+    pub fn init_renewal_allowance(
+        &mut self,
+        renewer: FieldElement,
+        erc20: FieldElement,
+        auto_renew_contract: FieldElement,
+        allowance: BigDecimal,
+    ) {
+        self.renewal_allowances
+            .entry((renewer, erc20, auto_renew_contract))
+            .or_insert(allowance);
+    }
+
+    // None if the renewal allowance of the renewer is unknown
+    pub fn remaining(
+        &self,
+        renewer: FieldElement,
+        erc20: FieldElement,
+        auto_renew_contract: FieldElement,
+    ) -> Option<Remaining> {
+        let key = (renewer, erc20, auto_renew_contract);
+        Some(Remaining {
+            balance: self
+                .balances
+                .get(&(renewer, erc20))
+                .cloned()
+                .unwrap_or_default(),
+            erc20_allowance: self.erc20_allowances.get(&key).cloned().unwrap_or_default(),
+            renewal_allowance: self.renewal_allowances.get(&key).cloned()?,
+        })
+    }
+
+    // Record the renewal of a domain, amount includes the tax
+    pub fn spend(
+        &mut self,
+        renewer: FieldElement,
+        erc20: FieldElement,
+        auto_renew_contract: FieldElement,
+        amount: &BigDecimal,
+    ) {
+        let key = (renewer, erc20, auto_renew_contract);
+        if let Some(balance) = self.balances.get_mut(&(renewer, erc20)) {
+            *balance -= amount;
+        }
+        if let Some(allowance) = self.erc20_allowances.get_mut(&key) {
+            *allowance -= amount;
+        }
+        if let Some(allowance) = self.renewal_allowances.get_mut(&key) {
+            *allowance -= amount;
+        }
+    }
+
+    // Give back the amount of a domain that won't be renewed with this price
+    pub fn refund(
+        &mut self,
+        renewer: FieldElement,
+        erc20: FieldElement,
+        auto_renew_contract: FieldElement,
+        amount: &BigDecimal,
+    ) {
+        self.spend(renewer, erc20, auto_renew_contract, &-amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn felt(value: u64) -> FieldElement {
+        FieldElement::from(value)
+    }
+
+    fn amount(value: u64) -> BigDecimal {
+        BigDecimal::from(value)
+    }
+
+    const RENEWER: u64 = 1;
+    const ERC20: u64 = 2;
+    const CONTRACT: u64 = 3;
+    const OTHER_CONTRACT: u64 = 4;
+
+    // renewal allowance and ERC20 approval are large enough, only the balance is limiting
+    fn ledger_with_balance(balance: u64) -> SpendingLedger {
+        let mut ledger = SpendingLedger::default();
+        ledger.set_balance(felt(RENEWER), felt(ERC20), amount(balance));
+        ledger.set_erc20_allowance(felt(RENEWER), felt(ERC20), felt(CONTRACT), amount(1000));
+        ledger.init_renewal_allowance(felt(RENEWER), felt(ERC20), felt(CONTRACT), amount(1000));
+        ledger
+    }
+
+    // Accept the domain if the renewer can still afford it, like the bot does for each candidate
+    fn try_renew(ledger: &mut SpendingLedger, contract: u64, price: u64) -> Result<(), SkipReason> {
+        let remaining = ledger
+            .remaining(felt(RENEWER), felt(ERC20), felt(contract))
+            .ok_or(SkipReason::MissingAllowance)?;
+        remaining.check(&amount(price))?;
+        ledger.spend(felt(RENEWER), felt(ERC20), felt(contract), &amount(price));
+        Ok(())
+    }
+
+    #[test]
+    fn only_affordable_domains_are_renewed() {
+        // five domains at 40 with a balance of 100
+        let mut ledger = ledger_with_balance(100);
+        let results: Vec<_> = (0..5)
+            .map(|_| try_renew(&mut ledger, CONTRACT, 40))
+            .collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert_eq!(results[2], Err(SkipReason::BalanceTooLow));
+        let remaining = ledger
+            .remaining(felt(RENEWER), felt(ERC20), felt(CONTRACT))
+            .unwrap();
+        assert_eq!(remaining.balance, amount(20));
+    }
+
+    #[test]
+    fn balance_is_shared_across_contracts() {
+        let mut ledger = ledger_with_balance(100);
+        ledger.set_erc20_allowance(
+            felt(RENEWER),
+            felt(ERC20),
+            felt(OTHER_CONTRACT),
+            amount(1000),
+        );
+        ledger.init_renewal_allowance(
+            felt(RENEWER),
+            felt(ERC20),
+            felt(OTHER_CONTRACT),
+            amount(1000),
+        );
+        assert_eq!(try_renew(&mut ledger, CONTRACT, 60), Ok(()));
+        assert_eq!(
+            try_renew(&mut ledger, OTHER_CONTRACT, 60),
+            Err(SkipReason::BalanceTooLow)
+        );
+        assert_eq!(try_renew(&mut ledger, OTHER_CONTRACT, 40), Ok(()));
+    }
+
+    #[test]
+    fn erc20_approval_is_per_contract() {
+        let mut ledger = ledger_with_balance(100);
+        ledger.init_renewal_allowance(
+            felt(RENEWER),
+            felt(ERC20),
+            felt(OTHER_CONTRACT),
+            amount(1000),
+        );
+        // the renewer only approved CONTRACT
+        assert_eq!(
+            try_renew(&mut ledger, OTHER_CONTRACT, 10),
+            Err(SkipReason::Erc20AllowanceTooLow)
+        );
+        assert_eq!(try_renew(&mut ledger, CONTRACT, 10), Ok(()));
+        let remaining = ledger
+            .remaining(felt(RENEWER), felt(ERC20), felt(CONTRACT))
+            .unwrap();
+        assert_eq!(remaining.erc20_allowance, amount(990));
+    }
+
+    #[test]
+    fn renewal_allowance_is_decremented() {
+        let mut ledger = SpendingLedger::default();
+        ledger.set_balance(felt(RENEWER), felt(ERC20), amount(1000));
+        ledger.set_erc20_allowance(felt(RENEWER), felt(ERC20), felt(CONTRACT), amount(1000));
+        ledger.init_renewal_allowance(felt(RENEWER), felt(ERC20), felt(CONTRACT), amount(50));
+        assert_eq!(try_renew(&mut ledger, CONTRACT, 30), Ok(()));
+        // the allowance is repeated on the next domain of the renewer and must not be reset
+        ledger.init_renewal_allowance(felt(RENEWER), felt(ERC20), felt(CONTRACT), amount(50));
+        assert_eq!(
+            try_renew(&mut ledger, CONTRACT, 30),
+            Err(SkipReason::RenewalAllowanceTooLow)
+        );
+        ledger.refund(felt(RENEWER), felt(ERC20), felt(CONTRACT), &amount(30));
+        assert_eq!(try_renew(&mut ledger, CONTRACT, 30), Ok(()));
+    }
+
+    #[test]
+    fn unknown_renewal_allowance_has_nothing_remaining() {
+        let ledger = ledger_with_balance(100);
+        assert!(ledger
+            .remaining(felt(RENEWER), felt(ERC20), felt(OTHER_CONTRACT))
+            .is_none());
+    }
+}
@@ -1,13 +1,25 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex, RwLock},
+};
 
 use self::status::status_client::StatusClient;
 use self::status::GetStatusRequest;
+use batch_sizer::BatchSizer;
+use bigdecimal::BigDecimal;
 use bot::renew_domains;
 use bson::doc;
+use fees::FeePolicy;
+use ledger::SpendingLedger;
+use models::States;
 use mongodb::{options::ClientOptions, Client as mongoClient};
+use nonce_manager::NonceManager;
+use quote_cache::QuoteCache;
+use report::{IndexerBlock, RunReport};
 use serde_derive::Serialize;
 use starknet::{
     accounts::SingleOwnerAccount,
+    core::types::{BlockId, BlockTag},
     providers::Provider,
     signers::{LocalWallet, SigningKey},
 };
@@ -18,14 +30,28 @@ pub mod status {
     tonic::include_proto!("apibara.sink.v1");
 }
 
+mod batch_sizer;
 mod bot;
 mod config;
+mod fees;
+mod journal;
+mod ledger;
 mod logger;
+mod metadata;
 mod models;
+mod nonce_manager;
 mod pipelines;
+mod price_oracle;
+mod priority;
+mod quote_cache;
+mod report;
 mod sales_tax;
+mod skip_reasons;
 mod starknet_utils;
 mod starknetid_utils;
+mod tax_records;
+mod tokens;
+mod tx_tracker;
 mod utils;
 
 #[derive(Serialize)]
@@ -44,20 +70,25 @@ struct LogPayload<'a> {
 
 #[tokio::main]
 async fn main() {
-    let conf = config::load();
+    let args = config::parse_args();
+    let conf = config::load(&args.config_path);
     let logger = logger::Logger::new(&conf.watchtower);
 
-    let states = sales_tax::load_sales_tax(&logger).await;
-    if states.states.is_empty() {
-        return;
-    }
-
     let client_options = ClientOptions::parse(&conf.database.connection_string)
         .await
         .unwrap();
     let client_options_metadata = ClientOptions::parse(&conf.database.connection_string_metadata)
         .await
         .unwrap();
+    let tokens = match tokens::load_tokens(&conf).await {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            logger
+                .async_severe(format!("Unable to load renewers tokens: {}", e))
+                .await;
+            return;
+        }
+    };
     let shared_state = Arc::new(models::AppState {
         db: mongoClient::with_options(client_options)
             .unwrap()
@@ -65,7 +96,14 @@ async fn main() {
         db_metadata: mongoClient::with_options(client_options_metadata)
             .unwrap()
             .database(&conf.database.metadata_name),
-        states,
+        states: RwLock::new(States::default()),
+        default_tax_rate: RwLock::new(BigDecimal::from(0)),
+        batch_sizer: Mutex::new(BatchSizer::new(&conf.batching)),
+        fee_policy: Mutex::new(FeePolicy::new(&conf.fees)),
+        nonce_manager: Mutex::new(NonceManager::new(&conf.transactions)),
+        quote_cache: QuoteCache::new(),
+        ledger: Mutex::new(SpendingLedger::default()),
+        tokens,
     });
     if shared_state
         .db
@@ -93,23 +131,63 @@ async fn main() {
         logger.info("Connected to metadata database");
     }
 
+    if let Some(tax_export) = &args.tax_export {
+        if let Err(e) = tax_records::export(&shared_state, tax_export).await {
+            logger
+                .async_severe(format!("Unable to export collected taxes: {}", e))
+                .await;
+        }
+        return;
+    }
+
+    if let Err(e) = sales_tax::reload_sales_tax(&conf, &shared_state, &logger).await {
+        logger
+            .async_severe(format!("Unable to load sales tax table: {}", e))
+            .await;
+        return;
+    }
+    if let Err(e) = sales_tax::load_default_rate(&conf, &shared_state) {
+        logger
+            .async_severe(format!("Unable to load default tax rate: {}", e))
+            .await;
+        return;
+    }
+
+    match fees::load_spent_today(&shared_state).await {
+        Ok(spent) => shared_state.fee_policy.lock().unwrap().restore_spent(spent),
+        Err(e) => {
+            logger
+                .async_severe(format!("Unable to load fees spent today: {}", e))
+                .await;
+            return;
+        }
+    }
+
     let provider = create_jsonrpc_client(&conf);
     let chainid = provider.chain_id().await.unwrap();
     let signer = LocalWallet::from(SigningKey::from_secret_scalar(conf.account.private_key));
-    let account = SingleOwnerAccount::new(
+    let mut account = SingleOwnerAccount::new(
         provider,
         signer,
         conf.account.address,
         chainid,
         starknet::accounts::ExecutionEncoding::New,
     );
+    // nonces read from the pending block count the transactions not included in a block yet
+    account.set_block_id(BlockId::Tag(BlockTag::Pending));
 
-    logger.info("Started");
+    if args.dry_run {
+        logger.info("Started in dry run mode, no transaction will be sent");
+    } else {
+        logger.info("Started");
+    }
     let mut need_to_check_status = true;
+    let mut indexer_blocks: Vec<IndexerBlock> = vec![];
     loop {
         if need_to_check_status {
             logger.info("Checking indexer status");
             let mut is_ready = true;
+            indexer_blocks.clear();
             'outer: for port in &conf.indexer_server.port {
                 let indexer_client =
                     StatusClient::connect(format!("{}:{}", conf.indexer_server.server_url, port))
@@ -120,6 +198,11 @@ async fn main() {
                         match indexer.get_status(request).await {
                             Ok(response) => {
                                 let res = response.into_inner();
+                                indexer_blocks.push(IndexerBlock {
+                                    port: *port,
+                                    current_block: res.current_block,
+                                    head_block: res.head_block,
+                                });
                                 if let (Some(current_block), Some(head_block)) =
                                     (res.current_block, res.head_block)
                                 {
@@ -159,16 +242,46 @@ async fn main() {
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
         } else {
             println!("[bot] Checking domains to renew");
+            let mut report = RunReport::new(args.dry_run, indexer_blocks.clone());
+            shared_state.batch_sizer.lock().unwrap().new_cycle();
+            // A table that can't be loaded or is invalid never replaces the active one
+            if let Err(e) = sales_tax::reload_sales_tax(&conf, &shared_state, &logger).await {
+                logger.severe(format!(
+                    "Unable to reload sales tax table, keeping the active one: {}",
+                    e
+                ));
+            }
+            if !args.dry_run {
+                if let Err(e) = journal::reconcile(&conf, &shared_state, &logger).await {
+                    logger.severe(format!("Unable to reconcile transaction journal: {}", e));
+                    sleep(std::time::Duration::from_secs(60)).await;
+                    continue;
+                }
+            }
             match bot::get_domains_ready_for_renewal(&conf, &shared_state, &logger).await {
-                Ok(aggregate_results) => {
+                Ok(candidates) => {
+                    report.candidates = candidates.candidates_count;
+                    report.altcoins_candidates = candidates.altcoins_candidates_count;
+                    report.eligible = candidates
+                        .grouped_results
+                        .values()
+                        .map(|result| result.domains.len())
+                        .sum();
+                    report.metadata = candidates.metadata_stats;
+                    report.allowance_discrepancies = candidates.allowance_discrepancies;
+                    report.expiry_drifts = candidates.expiry_drifts;
+                    report.set_skipped(candidates.skipped);
+                    let aggregate_results = candidates.grouped_results;
                     if !aggregate_results.is_empty() {
                         for (auto_renew_contract, result) in &aggregate_results {
                             match renew_domains(
                                 &conf,
+                                &shared_state,
                                 &account,
                                 result.clone(),
                                 auto_renew_contract,
                                 &logger,
+                                &mut report,
                             )
                             .await
                             {
@@ -200,6 +313,18 @@ async fn main() {
                     ));
                 }
             }
+            report.finish();
+            logger.info(report.summary());
+            if args.dry_run {
+                match report.save(&args.report_path) {
+                    Ok(_) => logger.info(format!("Dry run report saved to {}", args.report_path)),
+                    Err(e) => logger.severe(format!("Unable to save dry run report: {}", e)),
+                }
+                return;
+            }
+            if let Err(e) = report.store(&shared_state).await {
+                logger.severe(format!("Unable to store run report: {}", e));
+            }
             // Sleep for 24 hours
             sleep(std::time::Duration::from_secs(conf.renewals.delay)).await;
         }
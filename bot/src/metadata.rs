@@ -0,0 +1,122 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use bigdecimal::num_bigint::BigInt;
+use bson::doc;
+use futures::TryStreamExt;
+use serde::Serialize;
+
+use crate::models::{AppState, MetadataDoc, States};
+
+// Number of meta hashes resolved by a single $in query
+const CHUNK_SIZE: usize = 500;
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MetadataStats {
+    pub meta_hashes: usize,
+    pub documents: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub unknown_tax_states: Vec<UnknownTaxState>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UnknownTaxState {
+    pub code: String,
+    pub meta_hashes: usize,
+}
+
+// Tax states of all the meta hashes of a cycle, fetched once before evaluating candidates
+#[derive(Default)]
+pub struct TaxStates {
+    tax_states: HashMap<String, String>,
+    missing: Vec<String>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+// Meta hashes are stored in hex without prefix nor leading zeros in the metadata collection,
+// "0" means the domain has no metadata
+pub fn normalize_meta_hash(meta_hash: &str) -> Option<String> {
+    if meta_hash == "0" {
+        return None;
+    }
+    BigInt::parse_bytes(meta_hash.trim_start_matches("0x").as_bytes(), 16)
+        .map(|meta_hash| meta_hash.to_str_radix(16))
+}
+
+impl TaxStates {
+    pub async fn fetch(state: &AppState, meta_hashes: HashSet<String>) -> Result<Self> {
+        let meta_hashes: Vec<String> = meta_hashes.into_iter().collect();
+        let metadata_collection = state.db_metadata.collection::<MetadataDoc>("metadata");
+        let mut tax_states = HashMap::new();
+        for chunk in meta_hashes.chunks(CHUNK_SIZE) {
+            let documents: Vec<MetadataDoc> = metadata_collection
+                .find(doc! { "meta_hash": { "$in": chunk.to_vec() } }, None)
+                .await?
+                .try_collect()
+                .await?;
+            for document in documents {
+                tax_states.insert(document.meta_hash, document.tax_state);
+            }
+        }
+        let missing = meta_hashes
+            .into_iter()
+            .filter(|meta_hash| !tax_states.contains_key(meta_hash))
+            .collect();
+        Ok(TaxStates {
+            tax_states,
+            missing,
+            ..Default::default()
+        })
+    }
+
+    // Tax state of a normalized meta hash, None if it has no metadata document
+    pub fn get(&self, meta_hash: &str) -> Option<&str> {
+        match self.tax_states.get(meta_hash) {
+            Some(tax_state) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(tax_state)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    // Meta hashes without a metadata document
+    pub fn missing(&self) -> &[String] {
+        &self.missing
+    }
+
+    pub fn stats(&self) -> MetadataStats {
+        MetadataStats {
+            meta_hashes: self.tax_states.len() + self.missing.len(),
+            documents: self.tax_states.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            unknown_tax_states: vec![],
+        }
+    }
+
+    // Tax state codes of the fetched metadata documents missing from the sales tax table
+    pub fn unknown_tax_states(&self, states: &States) -> Vec<UnknownTaxState> {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for tax_state in self.tax_states.values() {
+            if !states.states.contains_key(tax_state) {
+                *counts.entry(tax_state).or_default() += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .map(|(code, meta_hashes)| UnknownTaxState {
+                code: code.to_string(),
+                meta_hashes,
+            })
+            .collect()
+    }
+}
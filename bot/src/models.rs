@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
 
 use bigdecimal::BigDecimal;
 use bson::DateTime;
@@ -6,10 +7,34 @@ use mongodb::Database;
 use serde::{Deserialize, Serialize};
 use starknet::core::types::FieldElement;
 
+use crate::batch_sizer::BatchSizer;
+use crate::config::TieBreaker;
+use crate::fees::FeePolicy;
+use crate::ledger::SpendingLedger;
+use crate::metadata::MetadataStats;
+use crate::nonce_manager::NonceManager;
+use crate::priority::{priority_order, reorder, Priority};
+use crate::quote_cache::QuoteCache;
+use crate::report::{AllowanceDiscrepancy, ExpiryDrift};
+use crate::sales_tax::deserialize_rate;
+use crate::skip_reasons::SkippedDomain;
+use crate::tokens::Token;
+use crate::tx_tracker::TxStatus;
+use crate::utils::decode_domain;
+
 pub struct AppState {
     pub db: Database,
     pub db_metadata: Database,
-    pub states: States,
+    pub states: RwLock<States>,
+    // rate of the default_rate policy, loaded at startup
+    pub default_tax_rate: RwLock<BigDecimal>,
+    pub batch_sizer: Mutex<BatchSizer>,
+    pub fee_policy: Mutex<FeePolicy>,
+    pub nonce_manager: Mutex<NonceManager>,
+    pub quote_cache: QuoteCache,
+    // funds left to each renewer in the current cycle
+    pub ledger: Mutex<SpendingLedger>,
+    pub tokens: HashMap<FieldElement, Token>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -68,6 +93,12 @@ pub struct AggregateResult {
     pub tax_price: BigDecimal,
     pub meta_hash: FieldElement,
     pub auto_renew_contract: FieldElement,
+    pub expiry: Option<i64>,
+    pub tax_state: Option<String>,
+    pub quote_valid_until: Option<i64>,
+    // renewal price in wei and tax rate, kept to reprice the domain with a new quote
+    pub eth_price: BigDecimal,
+    pub tax_rate: BigDecimal,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -78,6 +109,91 @@ pub struct AggregateResults {
     pub tax_prices: Vec<BigDecimal>,
     pub meta_hashes: Vec<FieldElement>,
     pub auto_renew_contracts: Vec<FieldElement>,
+    pub expiries: Vec<Option<i64>>,
+    pub tax_states: Vec<Option<String>>,
+    pub quotes_valid_until: Vec<Option<i64>>,
+    pub eth_prices: Vec<BigDecimal>,
+    pub tax_rates: Vec<BigDecimal>,
+}
+
+impl AggregateResults {
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    // Removes the first `size` domains and returns them as a new AggregateResults
+    pub fn drain_front(&mut self, size: usize) -> AggregateResults {
+        fn take_front<T>(values: &mut Vec<T>, size: usize) -> Vec<T> {
+            let rest = values.split_off(size.min(values.len()));
+            std::mem::replace(values, rest)
+        }
+        AggregateResults {
+            domains: take_front(&mut self.domains, size),
+            renewers: take_front(&mut self.renewers, size),
+            domain_prices: take_front(&mut self.domain_prices, size),
+            tax_prices: take_front(&mut self.tax_prices, size),
+            meta_hashes: take_front(&mut self.meta_hashes, size),
+            auto_renew_contracts: take_front(&mut self.auto_renew_contracts, size),
+            expiries: take_front(&mut self.expiries, size),
+            tax_states: take_front(&mut self.tax_states, size),
+            quotes_valid_until: take_front(&mut self.quotes_valid_until, size),
+            eth_prices: take_front(&mut self.eth_prices, size),
+            tax_rates: take_front(&mut self.tax_rates, size),
+        }
+    }
+
+    // Most urgent domains first so that they end up in the first batches
+    pub fn sort_by_priority(&mut self, tie_breaker: Option<TieBreaker>) {
+        let priorities: Vec<Priority> = (0..self.len())
+            .map(|i| {
+                Priority::new(
+                    &decode_domain(self.domains[i]),
+                    self.expiries[i],
+                    Some(self.domain_prices[i].clone()),
+                )
+            })
+            .collect();
+        self.select(&priority_order(&priorities, tie_breaker));
+    }
+
+    // Removes the domains for which keep is false and returns them as a new AggregateResults
+    pub fn split_off_where(&mut self, keep: &[bool]) -> AggregateResults {
+        let (kept, removed): (Vec<usize>, Vec<usize>) =
+            (0..self.len()).partition(|&i| keep.get(i).copied().unwrap_or(true));
+        let mut removed_results = self.clone();
+        removed_results.select(&removed);
+        self.select(&kept);
+        removed_results
+    }
+
+    // Keeps the domains at the given indices, in this order
+    fn select(&mut self, indices: &[usize]) {
+        reorder(&mut self.domains, indices);
+        reorder(&mut self.renewers, indices);
+        reorder(&mut self.domain_prices, indices);
+        reorder(&mut self.tax_prices, indices);
+        reorder(&mut self.meta_hashes, indices);
+        reorder(&mut self.auto_renew_contracts, indices);
+        reorder(&mut self.expiries, indices);
+        reorder(&mut self.tax_states, indices);
+        reorder(&mut self.quotes_valid_until, indices);
+        reorder(&mut self.eth_prices, indices);
+        reorder(&mut self.tax_rates, indices);
+    }
+}
+
+pub struct RenewalCandidates {
+    pub grouped_results: HashMap<FieldElement, AggregateResults>,
+    pub skipped: Vec<SkippedDomain>,
+    pub candidates_count: usize,
+    pub altcoins_candidates_count: usize,
+    pub metadata_stats: MetadataStats,
+    pub allowance_discrepancies: Vec<AllowanceDiscrepancy>,
+    pub expiry_drifts: Vec<ExpiryDrift>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -90,13 +206,19 @@ pub struct MetadataDoc {
 
 #[derive(Deserialize, Debug)]
 pub struct State {
-    pub rate: f32,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub rate: BigDecimal,
     #[serde(rename = "type")]
     pub type_: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct States {
+    #[serde(default)]
+    pub version: Option<String>,
+    // YYYY-MM-DD, the table isn't used before this date
+    #[serde(default)]
+    pub effective_date: Option<String>,
     pub states: HashMap<String, State>,
 }
 
@@ -106,4 +228,6 @@ pub struct TxResult {
     pub reverted: Option<bool>,
     pub revert_reason: Option<String>,
     pub domains_renewed: usize,
+    pub status: TxStatus,
+    pub sent_at: i64,
 }
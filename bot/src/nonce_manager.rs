@@ -0,0 +1,115 @@
+use starknet::{
+    accounts::AccountError,
+    core::types::{FieldElement, StarknetError},
+};
+
+use crate::{config::Transactions, starknet_utils::is_starknet_error};
+
+pub struct InFlightTx {
+    pub nonce: FieldElement,
+    pub tx_hash: FieldElement,
+    pub sent_at_block: u64,
+}
+
+// Hands out the nonces of the bot account across all auto renew contracts. The local nonce is
+// only trusted while it matches the chain, it is resynced whenever the chain disagrees.
+pub struct NonceManager {
+    config: Transactions,
+    next_nonce: Option<FieldElement>,
+    in_flight: Vec<InFlightTx>,
+    last_block: u64,
+}
+
+impl NonceManager {
+    pub fn new(config: &Transactions) -> Self {
+        NonceManager {
+            config: config.clone(),
+            next_nonce: None,
+            in_flight: vec![],
+            last_block: 0,
+        }
+    }
+
+    pub fn next_nonce(&self) -> Option<FieldElement> {
+        self.next_nonce
+    }
+
+    // Forget local state and restart from the nonce of the account on chain, used to replace
+    // stuck transactions
+    pub fn resync(&mut self, chain_nonce: FieldElement) {
+        self.next_nonce = Some(chain_nonce);
+        self.in_flight.clear();
+    }
+
+    // Restart from the nonce of the account on chain without reusing the nonce of a transaction
+    // still in flight
+    fn resume(&mut self, chain_nonce: FieldElement) {
+        self.next_nonce = match self.in_flight.iter().map(|tx| tx.nonce).max() {
+            Some(last_nonce) if last_nonce >= chain_nonce => Some(last_nonce + FieldElement::ONE),
+            _ => Some(chain_nonce),
+        };
+    }
+
+    // Read the nonce from chain before the next transaction, used after a nonce error. The
+    // transactions in flight are kept as they still hold their nonce.
+    pub fn invalidate(&mut self) {
+        self.next_nonce = None;
+    }
+
+    // The nonce is held by a transaction already in the mempool whose hash isn't known
+    pub fn skip(&mut self, nonce: FieldElement) {
+        match self.next_nonce {
+            Some(next_nonce) if next_nonce > nonce => {}
+            _ => self.next_nonce = Some(nonce + FieldElement::ONE),
+        }
+    }
+
+    // chain_nonce is read at the pending block. Transactions whose nonce has been consumed on
+    // chain are no longer in flight.
+    pub fn update(&mut self, chain_nonce: FieldElement, current_block: u64) {
+        self.last_block = current_block;
+        self.in_flight.retain(|tx| tx.nonce >= chain_nonce);
+        match self.next_nonce {
+            // the account was used outside of the bot
+            Some(next_nonce) if next_nonce < chain_nonce => self.resume(chain_nonce),
+            None => self.resume(chain_nonce),
+            _ => {}
+        }
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.in_flight.len() < self.config.max_in_flight
+    }
+
+    pub fn sent(&mut self, nonce: FieldElement, tx_hash: FieldElement) {
+        self.in_flight.push(InFlightTx {
+            nonce,
+            tx_hash,
+            sent_at_block: self.last_block,
+        });
+        self.next_nonce = Some(nonce + FieldElement::ONE);
+    }
+
+    // Transactions not included after stuck_after_blocks blocks
+    pub fn stuck_transactions(&self) -> Vec<&InFlightTx> {
+        self.in_flight
+            .iter()
+            .filter(|tx| tx.sent_at_block + self.config.stuck_after_blocks <= self.last_block)
+            .collect()
+    }
+}
+
+pub fn is_nonce_error<S, P>(error: &AccountError<S, P>) -> bool {
+    match error {
+        AccountError::Provider(e) => is_starknet_error(e, StarknetError::InvalidTransactionNonce),
+        _ => false,
+    }
+}
+
+// The same transaction was already submitted, it keeps its nonce
+pub fn is_duplicate_error<S, P>(error: &AccountError<S, P>) -> bool {
+    match error {
+        AccountError::Provider(e) => is_starknet_error(e, StarknetError::DuplicateTx),
+        _ => false,
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use bigdecimal::num_bigint::BigInt;
+use starknet::{
+    core::types::{BlockId, BlockTag, FieldElement, FunctionCall},
+    macros::selector,
+    providers::{jsonrpc::HttpTransport, JsonRpcClient, Provider},
+};
+use starknet_id::encode;
+
+use crate::{
+    config::Config, logger::Logger, starknet_utils::create_jsonrpc_client,
+    starknetid_utils::get_renewal_price_eth, utils::from_uint256,
+};
+
+// Prices returned by the pricing contract only depend on the number of characters of the
+// domain, so we query it once per length and reuse the result for the rest of the cycle.
+pub struct PriceOracle<'a> {
+    config: &'a Config,
+    provider: JsonRpcClient<HttpTransport>,
+    cache: HashMap<usize, BigInt>,
+}
+
+impl<'a> PriceOracle<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        PriceOracle {
+            config,
+            provider: create_jsonrpc_client(config),
+            cache: HashMap::new(),
+        }
+    }
+
+    pub async fn get_renewal_price(
+        &mut self,
+        domain: &str,
+        days: FieldElement,
+        logger: &Logger,
+    ) -> Result<BigInt> {
+        let domain_name = domain
+            .strip_suffix(".stark")
+            .ok_or_else(|| anyhow!("Invalid domain name: {:?}", domain))?;
+        // the pricing contract counts characters, not bytes
+        let domain_len = domain_name.chars().count();
+        if let Some(price) = self.cache.get(&domain_len) {
+            return Ok(price.clone());
+        }
+
+        let price = match self.fetch_renew_price(domain_name, days).await {
+            Ok(price) => price,
+            Err(e) if self.config.pricing.fallback_to_constants => {
+                logger.warning(format!(
+                    "Unable to fetch renewal price for domains of length {} from pricing contract, using hardcoded price: {}",
+                    domain_len, e
+                ));
+                get_renewal_price_eth(domain.to_string())
+            }
+            Err(e) => return Err(e),
+        };
+        self.cache.insert(domain_len, price.clone());
+        Ok(price)
+    }
+
+    async fn fetch_renew_price(&self, domain_name: &str, days: FieldElement) -> Result<BigInt> {
+        let domain_encoded =
+            encode(domain_name).map_err(|_| anyhow!("Failed to encode domain name"))?;
+        let call_result = self
+            .provider
+            .call(
+                FunctionCall {
+                    contract_address: self.config.contract.pricing,
+                    entry_point_selector: selector!("compute_renew_price"),
+                    calldata: vec![domain_encoded, days],
+                },
+                BlockId::Tag(BlockTag::Latest),
+            )
+            .await
+            .map_err(|e| anyhow!("Error while calling pricing contract: {}", e))?;
+
+        // compute_renew_price returns (erc20_address, price as u256)
+        match call_result.as_slice() {
+            [_, low, high] => Ok(from_uint256(*low, *high)),
+            _ => Err(anyhow!(
+                "Unexpected result from pricing contract: {:?}",
+                call_result
+            )),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::cmp::Ordering;
+
+use bigdecimal::BigDecimal;
+
+use crate::config::TieBreaker;
+
+// Domains closest to expiring are renewed first, unknown expiries last
+pub struct Priority {
+    pub expiry: Option<i64>,
+    pub name_length: usize,
+    pub price: Option<BigDecimal>,
+}
+
+impl Priority {
+    pub fn new(domain: &str, expiry: Option<i64>, price: Option<BigDecimal>) -> Self {
+        Priority {
+            expiry,
+            name_length: domain
+                .strip_suffix(".stark")
+                .unwrap_or(domain)
+                .chars()
+                .count(),
+            price,
+        }
+    }
+
+    fn compare(&self, other: &Priority, tie_breaker: Option<TieBreaker>) -> Ordering {
+        let by_expiry = match (self.expiry, other.expiry) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        by_expiry.then_with(|| match tie_breaker {
+            Some(TieBreaker::ShortestName) => self.name_length.cmp(&other.name_length),
+            Some(TieBreaker::MostExpensive) => other.price.cmp(&self.price),
+            None => Ordering::Equal,
+        })
+    }
+}
+
+// Indices of the priorities from the most to the least urgent, equal ones keep their order
+pub fn priority_order(priorities: &[Priority], tie_breaker: Option<TieBreaker>) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..priorities.len()).collect();
+    order.sort_by(|&a, &b| priorities[a].compare(&priorities[b], tie_breaker));
+    order
+}
+
+// Keeps the values at the given indices, in this order
+pub fn reorder<T>(values: &mut Vec<T>, order: &[usize]) {
+    let mut taken: Vec<Option<T>> = values.drain(..).map(Some).collect();
+    *values = order
+        .iter()
+        .filter_map(|&i| taken.get_mut(i).and_then(Option::take))
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priority(domain: &str, expiry: Option<i64>, price: u64) -> Priority {
+        Priority::new(domain, expiry, Some(BigDecimal::from(price)))
+    }
+
+    #[test]
+    fn closest_expiry_first_and_unknown_last() {
+        let priorities = vec![
+            priority("unknown.stark", None, 10),
+            priority("later.stark", Some(2000), 10),
+            priority("soon.stark", Some(1000), 10),
+        ];
+        assert_eq!(priority_order(&priorities, None), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn equal_expiries_keep_their_order_without_tie_breaker() {
+        let priorities = vec![
+            priority("longest.stark", Some(1000), 10),
+            priority("abc.stark", Some(1000), 20),
+            priority("soon.stark", Some(500), 10),
+        ];
+        assert_eq!(priority_order(&priorities, None), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn shortest_name_breaks_ties() {
+        let priorities = vec![
+            priority("longest.stark", Some(1000), 10),
+            // 3 characters but more bytes than "abcd"
+            priority("ééé.stark", Some(1000), 10),
+            priority("abcd.stark", Some(1000), 10),
+        ];
+        assert_eq!(
+            priority_order(&priorities, Some(TieBreaker::ShortestName)),
+            vec![1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn most_expensive_breaks_ties() {
+        let priorities = vec![
+            priority("cheap.stark", Some(1000), 10),
+            priority("expensive.stark", Some(1000), 30),
+            priority("unknown.stark", Some(1000), 20),
+            priority("soon.stark", Some(500), 1),
+        ];
+        assert_eq!(
+            priority_order(&priorities, Some(TieBreaker::MostExpensive)),
+            vec![3, 1, 2, 0]
+        );
+    }
+
+    #[test]
+    fn reorder_follows_the_priority_order() {
+        let mut values = vec!["a", "b", "c"];
+        reorder(&mut values, &[2, 0, 1]);
+        assert_eq!(values, vec!["c", "a", "b"]);
+    }
+}
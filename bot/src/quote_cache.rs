@@ -0,0 +1,280 @@
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use bigdecimal::{num_bigint::BigInt, BigDecimal};
+use chrono::Utc;
+use starknet::core::types::FieldElement;
+use tokio::time::{sleep, Duration as TokioDuration};
+
+use crate::{
+    config::{Config, Renewer},
+    starknetid_utils::get_altcoin_quote,
+    tokens::QUOTE_DECIMALS,
+};
+
+// Quotes are considered stale this many seconds before their max validity so that a batch
+// has time to be included before the contract rejects it
+pub const QUOTE_VALIDITY_MARGIN: i64 = 60;
+
+// Attempts to fetch a quote before giving up until the next retry
+const FETCH_ATTEMPTS: u32 = 3;
+// Delay before fetching the quote of a token again after a failure, doubled on every failure
+const RETRY_DELAY: i64 = 30;
+const MAX_RETRY_DELAY: i64 = 1800;
+
+#[derive(Clone, Debug)]
+pub struct Quote {
+    pub quote: BigInt,
+    pub valid_until: i64,
+}
+
+impl Quote {
+    pub fn is_valid(&self) -> bool {
+        is_quote_valid(self.valid_until)
+    }
+}
+
+pub fn is_quote_valid(valid_until: i64) -> bool {
+    Utc::now().timestamp() + QUOTE_VALIDITY_MARGIN < valid_until
+}
+
+struct QuoteFailure {
+    failures: u32,
+    retry_at: i64,
+    error: String,
+}
+
+// Altcoin quotes of the starknet.id api per ERC20 address, refetched once expired. Tokens whose
+// quote can't be fetched aren't queried again before their retry delay.
+pub struct QuoteCache {
+    client: reqwest::Client,
+    // last accepted quote of each token, the reference of the deviation check once expired
+    quotes: Mutex<HashMap<String, Quote>>,
+    failures: Mutex<HashMap<String, QuoteFailure>>,
+}
+
+impl QuoteCache {
+    pub fn new() -> Self {
+        QuoteCache {
+            client: reqwest::Client::new(),
+            quotes: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get_quote(&self, config: &Config, erc20: &str) -> Result<Quote> {
+        if let Some(quote) = self.quotes.lock().unwrap().get(erc20) {
+            if quote.is_valid() {
+                return Ok(quote.clone());
+            }
+        }
+        if let Some(failure) = self.failures.lock().unwrap().get(erc20) {
+            if Utc::now().timestamp() < failure.retry_at {
+                return Err(anyhow!(
+                    "Quote unavailable until {}: {}",
+                    failure.retry_at,
+                    failure.error
+                ));
+            }
+        }
+
+        let mut attempt = 0;
+        let fetched = loop {
+            attempt += 1;
+            match self.fetch_quote(config, erc20).await {
+                Ok(quote) => break Ok(quote),
+                Err(e) if attempt >= FETCH_ATTEMPTS => break Err(e),
+                Err(_) => sleep(TokioDuration::from_secs(2u64.pow(attempt))).await,
+            }
+        };
+        let quote = match fetched {
+            Ok(quote) => quote,
+            Err(e) => {
+                self.record_failure(erc20, &e, None);
+                return Err(e);
+            }
+        };
+
+        // A quote out of bounds isn't fetched again before the next cycle, the api would likely
+        // return the same bad quote
+        if let Err(e) = self.check_quote(config, erc20, &quote) {
+            self.record_failure(erc20, &e, Some(config.renewals.delay as i64));
+            return Err(e);
+        }
+        self.failures.lock().unwrap().remove(erc20);
+        self.quotes
+            .lock()
+            .unwrap()
+            .insert(erc20.to_string(), quote.clone());
+        Ok(quote)
+    }
+
+    // retry_after overrides the exponential retry delay
+    fn record_failure(&self, erc20: &str, error: &anyhow::Error, retry_after: Option<i64>) {
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures
+            .entry(erc20.to_string())
+            .or_insert_with(|| QuoteFailure {
+                failures: 0,
+                retry_at: 0,
+                error: String::new(),
+            });
+        failure.failures += 1;
+        let delay = retry_after.unwrap_or_else(|| {
+            RETRY_DELAY
+                .saturating_mul(1 << (failure.failures - 1).min(16))
+                .min(MAX_RETRY_DELAY)
+        });
+        failure.retry_at = Utc::now().timestamp() + delay;
+        failure.error = error.to_string();
+    }
+
+    // The deviation is checked against the last accepted quote so that a bad quote can never
+    // become the reference of the next one
+    fn check_quote(&self, config: &Config, erc20: &str, quote: &Quote) -> Result<()> {
+        let renewer = match FieldElement::from_hex_be(erc20)
+            .ok()
+            .and_then(|erc20| config.renewer_of_token(&erc20))
+        {
+            Some(renewer) => renewer,
+            None => return Ok(()),
+        };
+        let previous = self
+            .quotes
+            .lock()
+            .unwrap()
+            .get(erc20)
+            .map(|previous| previous.quote.clone());
+        check_bounds(renewer, &quote.quote, previous.as_ref())
+    }
+
+    async fn fetch_quote(&self, config: &Config, erc20: &str) -> Result<Quote> {
+        let result = get_altcoin_quote(config, &self.client, erc20).await?;
+        let quote = Quote {
+            quote: BigInt::from_str(&result.quote)
+                .map_err(|e| anyhow!("Invalid quote {}: {}", result.quote, e))?,
+            valid_until: result.max_quote_validity as i64,
+        };
+        if !quote.is_valid() {
+            return Err(anyhow!(
+                "Quote for {} is already expired (valid until {})",
+                erc20,
+                quote.valid_until
+            ));
+        }
+        Ok(quote)
+    }
+}
+
+// Reject quotes outside of the bounds configured for the token or too far from the last
+// accepted one
+pub fn check_bounds(renewer: &Renewer, quote: &BigInt, previous: Option<&BigInt>) -> Result<()> {
+    let value = BigDecimal::new(quote.clone(), QUOTE_DECIMALS.into());
+    if let Some(min_quote) = renewer.min_quote {
+        if value < to_decimal(min_quote)? {
+            return Err(anyhow!("Quote {} is below min quote {}", value, min_quote));
+        }
+    }
+    if let Some(max_quote) = renewer.max_quote {
+        if value > to_decimal(max_quote)? {
+            return Err(anyhow!("Quote {} is above max quote {}", value, max_quote));
+        }
+    }
+    if let (Some(max_deviation), Some(previous)) = (renewer.max_quote_deviation, previous) {
+        let previous = BigDecimal::new(previous.clone(), QUOTE_DECIMALS.into());
+        if previous > BigDecimal::from(0)
+            && (&value - &previous).abs() / &previous > to_decimal(max_deviation)?
+        {
+            return Err(anyhow!(
+                "Quote {} deviates more than {} from previous quote {}",
+                value,
+                max_deviation,
+                previous
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn to_decimal(value: f64) -> Result<BigDecimal> {
+    BigDecimal::from_str(&value.to_string()).map_err(|e| anyhow!("Invalid value {}: {}", value, e))
+}
+
+// Price limit sent to batch_renew, the contract charges the price computed with its own quote.
+// Only the calldata uses it, everything else keeps the quoted price.
+pub fn apply_slippage(price: &BigDecimal, slippage: Option<f64>) -> BigDecimal {
+    match slippage.and_then(|slippage| to_decimal(slippage).ok()) {
+        Some(slippage) => (price * (BigDecimal::from(1) + slippage)).with_scale(0),
+        None => price.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renewer(min_quote: Option<f64>, max_quote: Option<f64>, deviation: Option<f64>) -> Renewer {
+        Renewer {
+            address: FieldElement::ONE,
+            renewal_contract: FieldElement::TWO,
+            decimals: None,
+            symbol: None,
+            min_quote,
+            max_quote,
+            max_quote_deviation: deviation,
+            slippage: None,
+        }
+    }
+
+    // quotes are scaled to 18 decimals
+    fn quote(value: &str) -> BigInt {
+        BigDecimal::from_str(value)
+            .unwrap()
+            .with_scale(QUOTE_DECIMALS.into())
+            .as_bigint_and_exponent()
+            .0
+    }
+
+    #[test]
+    fn slippage_raises_the_price_limit() {
+        let price = BigDecimal::from(4_201_400_000_000_000_000u64);
+        assert_eq!(
+            apply_slippage(&price, Some(0.05)),
+            BigDecimal::from(4_411_470_000_000_000_000u64)
+        );
+    }
+
+    #[test]
+    fn slippage_is_rounded_down_to_an_integer() {
+        let price = BigDecimal::from(9_333_333);
+        assert_eq!(
+            apply_slippage(&price, Some(0.01)),
+            BigDecimal::from(9_426_666)
+        );
+    }
+
+    #[test]
+    fn no_slippage_keeps_the_price() {
+        let price = BigDecimal::from(8_400_000);
+        assert_eq!(apply_slippage(&price, None), price);
+    }
+
+    #[test]
+    fn quotes_outside_of_bounds_are_rejected() {
+        let renewer = renewer(Some(1000.0), Some(2000.0), None);
+        assert!(check_bounds(&renewer, &quote("1500.5"), None).is_ok());
+        assert!(check_bounds(&renewer, &quote("999.9"), None).is_err());
+        assert!(check_bounds(&renewer, &quote("2000.1"), None).is_err());
+    }
+
+    #[test]
+    fn quotes_deviating_from_the_previous_one_are_rejected() {
+        let renewer = renewer(None, None, Some(0.1));
+        let previous = quote("1500");
+        assert!(check_bounds(&renewer, &quote("1600"), Some(&previous)).is_ok());
+        assert!(check_bounds(&renewer, &quote("1400"), Some(&previous)).is_ok());
+        assert!(check_bounds(&renewer, &quote("1700"), Some(&previous)).is_err());
+        // the first quote of a token has nothing to deviate from
+        assert!(check_bounds(&renewer, &quote("1700"), None).is_ok());
+    }
+}
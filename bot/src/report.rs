@@ -0,0 +1,258 @@
+use std::{cmp::Reverse, fs};
+
+use anyhow::Result;
+use bigdecimal::BigDecimal;
+use chrono::Utc;
+use serde::Serialize;
+use starknet::core::types::FieldElement;
+
+use crate::{
+    metadata::MetadataStats,
+    models::{AggregateResults, AppState, TxResult},
+    skip_reasons::{SkipReason, SkipSummary, SkippedDomain},
+    tx_tracker::TxStatus,
+    utils::{decode_domain, to_hex},
+};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct IndexerBlock {
+    pub port: u16,
+    pub current_block: Option<u64>,
+    pub head_block: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Simulated,
+    Pending,
+    Succeeded,
+    Reverted,
+    Rejected,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchReport {
+    pub auto_renew_contract: String,
+    pub tx_hash: Option<String>,
+    pub status: BatchStatus,
+    pub domains: Vec<String>,
+    pub renewers: Vec<String>,
+    pub domain_prices: Vec<String>,
+    pub tax_prices: Vec<String>,
+    pub meta_hashes: Vec<String>,
+    pub estimated_fee: Option<u64>,
+    pub tx_status: Option<TxStatus>,
+    pub revert_reason: Option<String>,
+}
+
+// ERC20 allowance of a renewer to an auto renew contract differing between the indexer and the chain
+#[derive(Serialize, Debug, Clone)]
+pub struct AllowanceDiscrepancy {
+    pub renewer: String,
+    pub erc20: String,
+    pub auto_renew_contract: String,
+    pub indexed: String,
+    pub on_chain: String,
+}
+
+// Expiry of a domain differing between the indexed domains collection and the naming contract
+#[derive(Serialize, Debug, Clone)]
+pub struct ExpiryDrift {
+    pub domain: String,
+    pub indexed_expiry: Option<i64>,
+    pub onchain_expiry: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkipCount {
+    pub reason: SkipReason,
+    pub count: usize,
+}
+
+// Report of a renewal cycle, saved in the auto_renew_runs collection or in a file in dry run mode
+#[derive(Serialize, Debug)]
+pub struct RunReport {
+    pub dry_run: bool,
+    pub started_at: i64,
+    pub ended_at: Option<i64>,
+    pub indexer_blocks: Vec<IndexerBlock>,
+    pub candidates: usize,
+    pub altcoins_candidates: usize,
+    pub eligible: usize,
+    pub metadata: MetadataStats,
+    pub allowance_discrepancies: Vec<AllowanceDiscrepancy>,
+    pub expiry_drifts: Vec<ExpiryDrift>,
+    pub skipped_by_reason: Vec<SkipCount>,
+    pub skipped: Vec<SkippedDomain>,
+    pub batches: Vec<BatchReport>,
+}
+
+impl RunReport {
+    pub fn new(dry_run: bool, indexer_blocks: Vec<IndexerBlock>) -> Self {
+        RunReport {
+            dry_run,
+            started_at: Utc::now().timestamp(),
+            ended_at: None,
+            indexer_blocks,
+            candidates: 0,
+            altcoins_candidates: 0,
+            eligible: 0,
+            metadata: MetadataStats::default(),
+            allowance_discrepancies: vec![],
+            expiry_drifts: vec![],
+            skipped_by_reason: vec![],
+            skipped: vec![],
+            batches: vec![],
+        }
+    }
+
+    pub fn set_skipped(&mut self, skipped: Vec<SkippedDomain>) {
+        self.skipped = skipped;
+        self.update_skip_counts();
+    }
+
+    pub fn skip_batch(
+        &mut self,
+        auto_renew_contract: FieldElement,
+        erc20: String,
+        batch: &AggregateResults,
+        reason: SkipReason,
+        details: Option<String>,
+    ) {
+        for (domain, renewer) in batch.domains.iter().zip(&batch.renewers) {
+            self.skipped.push(SkippedDomain {
+                domain: decode_domain(*domain),
+                renewer: to_hex(*renewer),
+                auto_renew_contract: to_hex(auto_renew_contract),
+                erc20: erc20.clone(),
+                reason,
+                details: details.clone(),
+            });
+        }
+        self.update_skip_counts();
+    }
+
+    fn update_skip_counts(&mut self) {
+        let mut skipped_by_reason: Vec<SkipCount> = SkipSummary::new(&self.skipped)
+            .by_reason
+            .into_iter()
+            .map(|(reason, count)| SkipCount { reason, count })
+            .collect();
+        skipped_by_reason.sort_by_key(|skip_count| Reverse(skip_count.count));
+        self.skipped_by_reason = skipped_by_reason;
+    }
+
+    pub fn add_batch(
+        &mut self,
+        auto_renew_contract: FieldElement,
+        aggregate_results: &AggregateResults,
+        tx_hash: Option<FieldElement>,
+        estimated_fee: Option<u64>,
+        revert_reason: Option<String>,
+    ) {
+        let to_strings = |values: &[BigDecimal]| -> Vec<String> {
+            values.iter().map(|v| v.to_string()).collect()
+        };
+        self.batches.push(BatchReport {
+            auto_renew_contract: to_hex(auto_renew_contract),
+            tx_hash: tx_hash.map(to_hex),
+            status: if self.dry_run {
+                BatchStatus::Simulated
+            } else {
+                BatchStatus::Pending
+            },
+            domains: aggregate_results
+                .domains
+                .iter()
+                .map(|domain| decode_domain(*domain))
+                .collect(),
+            renewers: aggregate_results
+                .renewers
+                .iter()
+                .map(|r| to_hex(*r))
+                .collect(),
+            domain_prices: to_strings(&aggregate_results.domain_prices),
+            tax_prices: to_strings(&aggregate_results.tax_prices),
+            meta_hashes: aggregate_results
+                .meta_hashes
+                .iter()
+                .map(|h| to_hex(*h))
+                .collect(),
+            estimated_fee,
+            tx_status: None,
+            revert_reason,
+        });
+    }
+
+    // Update the status of sent batches with the latest known transaction results
+    pub fn update_transactions(&mut self, tx_results: &[TxResult]) {
+        for tx_result in tx_results {
+            let tx_hash = to_hex(tx_result.tx_hash);
+            if let Some(batch) = self
+                .batches
+                .iter_mut()
+                .find(|batch| batch.tx_hash.as_ref() == Some(&tx_hash))
+            {
+                batch.tx_status = Some(tx_result.status);
+                match tx_result.reverted {
+                    Some(true) if tx_result.status == TxStatus::Rejected => {
+                        batch.status = BatchStatus::Rejected;
+                        batch.revert_reason = tx_result.revert_reason.clone();
+                    }
+                    Some(true) => {
+                        batch.status = BatchStatus::Reverted;
+                        batch.revert_reason = tx_result.revert_reason.clone();
+                    }
+                    Some(false) => batch.status = BatchStatus::Succeeded,
+                    None => {}
+                }
+            }
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let domains: usize = self.batches.iter().map(|b| b.domains.len()).sum();
+        let mut failing = self
+            .batches
+            .iter()
+            .filter(|b| b.revert_reason.is_some())
+            .count();
+        // no transaction is sent in dry runs, the batches failing are the ones isolated by the
+        // simulation
+        if self.dry_run {
+            failing += self
+                .skipped
+                .iter()
+                .filter(|skipped| skipped.reason == SkipReason::FeeEstimationFailed)
+                .count();
+        }
+        let total_fee: u64 = self.batches.iter().filter_map(|b| b.estimated_fee).sum();
+        format!(
+            "{}{} batches for {} domains, {} batches failed, total estimated fee {} wei",
+            if self.dry_run { "Dry run: " } else { "" },
+            self.batches.len(),
+            domains,
+            failing,
+            total_fee
+        )
+    }
+
+    pub fn finish(&mut self) {
+        self.ended_at = Some(Utc::now().timestamp());
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub async fn store(&self, state: &AppState) -> Result<()> {
+        state
+            .db
+            .collection::<RunReport>("auto_renew_runs")
+            .insert_one(self, None)
+            .await?;
+        Ok(())
+    }
+}
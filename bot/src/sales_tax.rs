@@ -1,21 +1,196 @@
-use crate::{logger::Logger, models::States};
-use std::{collections::HashMap, fs};
-
-pub async fn load_sales_tax(logger: &Logger) -> States {
-    match fs::read_to_string("./bot/src/sales_tax.json") {
-        Ok(data) => match serde_json::from_str(&data) {
-            Ok(states) => states,
-            Err(e) => {
-                logger.severe(format!("Unable to parse sales tax file: {}", e));
-                States {
-                    states: HashMap::new(),
-                }
+use crate::{
+    config::{Config, SalesTaxSource},
+    logger::Logger,
+    models::{AppState, States},
+};
+use anyhow::{anyhow, Result};
+use bigdecimal::{BigDecimal, ParseBigDecimalError};
+use bson::doc;
+use chrono::{NaiveDate, Utc};
+use mongodb::options::FindOneOptions;
+use serde::{Deserialize, Deserializer};
+use std::{fs, str::FromStr};
+
+const KNOWN_TAX_TYPES: [&str; 3] = ["vat", "gst", "sales_tax"];
+
+async fn fetch_sales_tax(config: &Config, state: &AppState) -> Result<States> {
+    let location = &config.sales_tax.location;
+    match config.sales_tax.source {
+        SalesTaxSource::File => Ok(serde_json::from_str(&fs::read_to_string(location)?)?),
+        SalesTaxSource::Http => {
+            let data = reqwest::get(location)
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            Ok(serde_json::from_str(&data)?)
+        }
+        // The collection holds every version of the table, the latest effective one is used
+        SalesTaxSource::Mongodb => {
+            let today = Utc::now().date_naive().format("%Y-%m-%d").to_string();
+            state
+                .db
+                .collection::<States>(location)
+                .find_one(
+                    doc! { "effective_date": { "$lte": today } },
+                    FindOneOptions::builder()
+                        .sort(doc! { "effective_date": -1 })
+                        .build(),
+                )
+                .await?
+                .ok_or_else(|| anyhow!("No effective sales tax table in collection {}", location))
+        }
+    }
+}
+
+pub fn validate_sales_tax(states: &States) -> Result<()> {
+    if states.states.is_empty() {
+        return Err(anyhow!("Sales tax table is empty"));
+    }
+    if let Some(effective_date) = &states.effective_date {
+        NaiveDate::parse_from_str(effective_date, "%Y-%m-%d")
+            .map_err(|e| anyhow!("Invalid effective date {}: {}", effective_date, e))?;
+    }
+    for (name, state) in &states.states {
+        if state.rate < BigDecimal::from(0) || state.rate >= BigDecimal::from(1) {
+            return Err(anyhow!("Invalid rate {} for {}", state.rate, name));
+        }
+        if !KNOWN_TAX_TYPES.contains(&state.type_.as_str()) {
+            return Err(anyhow!("Unknown tax type {} for {}", state.type_, name));
+        }
+    }
+    Ok(())
+}
+
+// Load the sales tax table from the configured source and replace the active one in AppState
+// once it is validated and effective
+pub async fn reload_sales_tax(config: &Config, state: &AppState, logger: &Logger) -> Result<()> {
+    let states = fetch_sales_tax(config, state).await?;
+    validate_sales_tax(&states)?;
+
+    let has_active_table = !state.states.read().unwrap().states.is_empty();
+    if let Some(effective_date) = &states.effective_date {
+        let today = Utc::now().date_naive().format("%Y-%m-%d").to_string();
+        if *effective_date > today {
+            if !has_active_table {
+                return Err(anyhow!(
+                    "Sales tax table is only effective from {}",
+                    effective_date
+                ));
             }
-        },
-        Err(e) => {
-            logger.severe(format!("Unable to load sales tax file: {}", e));
-            States {
-                states: HashMap::new(),
+            return Ok(());
+        }
+    }
+
+    let mut active = state.states.write().unwrap();
+    if active.states.is_empty() || active.version != states.version {
+        logger.info(format!(
+            "Loaded sales tax table version {} with {} states",
+            states.version.as_deref().unwrap_or("unknown"),
+            states.states.len()
+        ));
+    }
+    *active = states;
+    Ok(())
+}
+
+// The rate applied to unknown tax states is parsed and validated once at startup, an invalid
+// rate stops the bot instead of skipping every domain with an unknown tax state
+pub fn load_default_rate(config: &Config, state: &AppState) -> Result<()> {
+    let rate = config.sales_tax.default_rate;
+    let default_rate =
+        parse_rate(rate).map_err(|e| anyhow!("Invalid default tax rate {}: {}", rate, e))?;
+    if default_rate < BigDecimal::from(0) || default_rate >= BigDecimal::from(1) {
+        return Err(anyhow!("Invalid default tax rate {}", default_rate));
+    }
+    *state.default_tax_rate.write().unwrap() = default_rate;
+    Ok(())
+}
+
+// Rates are written as decimals in the json file (0.077 for 7.7%). They are parsed from their
+// shortest representation so that no float error ends up in the tax amount.
+pub fn deserialize_rate<'de, D>(deserializer: D) -> Result<BigDecimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let rate = f64::deserialize(deserializer)?;
+    parse_rate(rate).map_err(serde::de::Error::custom)
+}
+
+pub fn parse_rate(rate: f64) -> Result<BigDecimal, ParseBigDecimalError> {
+    BigDecimal::from_str(&rate.to_string())
+}
+
+// Tax amount in wei for a price in wei, rounded down to match the price quoted by the frontend
+pub fn compute_tax(price: &BigDecimal, rate: &BigDecimal) -> BigDecimal {
+    (price * rate).with_scale(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_states() -> States {
+        serde_json::from_str(include_str!("sales_tax.json")).unwrap()
+    }
+
+    fn wei(amount: &str) -> BigDecimal {
+        BigDecimal::from_str(amount).unwrap()
+    }
+
+    #[test]
+    fn rates_are_parsed_exactly() {
+        let states = load_states();
+        assert_eq!(states.states["switzerland"].rate, wei("0.077"));
+        for (name, state) in &states.states {
+            assert!(
+                state.rate >= BigDecimal::from(0) && state.rate < BigDecimal::from(1),
+                "invalid rate for {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn sales_tax_file_is_valid() {
+        validate_sales_tax(&load_states()).unwrap();
+    }
+
+    #[test]
+    fn switzerland_vat_is_not_rounded_to_a_percent() {
+        let states = load_states();
+        let rate = &states.states["switzerland"].rate;
+        // 0.0028 ETH renewal
+        assert_eq!(
+            compute_tax(&wei("2800000000000000"), rate),
+            wei("215600000000000")
+        );
+        assert_eq!(
+            compute_tax(&wei("1000000000000000000"), rate),
+            wei("77000000000000000")
+        );
+    }
+
+    #[test]
+    fn tax_is_rounded_down_to_the_wei() {
+        let states = load_states();
+        let rate = &states.states["switzerland"].rate;
+        // 999 * 0.077 = 76.923
+        assert_eq!(compute_tax(&wei("999"), rate), wei("76"));
+        assert_eq!(compute_tax(&wei("1"), rate), wei("0"));
+    }
+
+    #[test]
+    fn zero_rate_states_pay_no_tax() {
+        let states = load_states();
+        for (name, state) in &states.states {
+            if name != "switzerland" {
+                assert_eq!(
+                    compute_tax(&wei("2800000000000000"), &state.rate),
+                    BigDecimal::from(0),
+                    "unexpected tax for {}",
+                    name
+                );
             }
         }
     }
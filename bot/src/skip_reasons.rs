@@ -0,0 +1,122 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Disabled,
+    MissingAllowance,
+    Erc20AllowanceTooLow,
+    RenewalAllowanceTooLow,
+    BalanceTooLow,
+    InvalidDomainName,
+    PriceUnavailable,
+    AlreadySubmitted,
+    FeeEstimationFailed,
+    GasPriceTooHigh,
+    FeeBudgetExceeded,
+    UnknownTaxState,
+    StaleQuote,
+    QuoteUnavailable,
+    RecentlyRenewed,
+    AlreadyRenewedOnChain,
+    ExpiryOutsideWindow,
+    NotRenewed,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            SkipReason::Disabled => "auto renewal disabled",
+            SkipReason::MissingAllowance => "missing renewal allowance",
+            SkipReason::Erc20AllowanceTooLow => "erc20 allowance too low",
+            SkipReason::RenewalAllowanceTooLow => "renewal allowance too low",
+            SkipReason::BalanceTooLow => "balance too low",
+            SkipReason::InvalidDomainName => "invalid domain name",
+            SkipReason::PriceUnavailable => "renewal price unavailable",
+            SkipReason::AlreadySubmitted => "renewal already submitted",
+            SkipReason::FeeEstimationFailed => "fee estimation failed",
+            SkipReason::GasPriceTooHigh => "gas price too high",
+            SkipReason::FeeBudgetExceeded => "daily fee budget exceeded",
+            SkipReason::UnknownTaxState => "unknown tax state",
+            SkipReason::StaleQuote => "quote expired before sending",
+            SkipReason::QuoteUnavailable => "quote unavailable",
+            SkipReason::RecentlyRenewed => "renewed within the minimum interval",
+            SkipReason::AlreadyRenewedOnChain => "already renewed on chain",
+            SkipReason::ExpiryOutsideWindow => "on chain expiry outside the renewal window",
+            SkipReason::NotRenewed => "not renewed by a successful transaction",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SkippedDomain {
+    pub domain: String,
+    pub renewer: String,
+    pub auto_renew_contract: String,
+    pub erc20: String,
+    pub reason: SkipReason,
+    pub details: Option<String>,
+}
+
+// Number of skipped domains per reason, per auto renew contract and per token
+#[derive(Default, Debug)]
+pub struct SkipSummary {
+    pub total: usize,
+    pub by_reason: HashMap<SkipReason, usize>,
+    pub by_contract: HashMap<String, HashMap<SkipReason, usize>>,
+    pub by_token: HashMap<String, HashMap<SkipReason, usize>>,
+}
+
+impl SkipSummary {
+    pub fn new(skipped: &[SkippedDomain]) -> Self {
+        let mut summary = SkipSummary::default();
+        for skipped_domain in skipped {
+            summary.total += 1;
+            *summary.by_reason.entry(skipped_domain.reason).or_default() += 1;
+            *summary
+                .by_contract
+                .entry(skipped_domain.auto_renew_contract.clone())
+                .or_default()
+                .entry(skipped_domain.reason)
+                .or_default() += 1;
+            *summary
+                .by_token
+                .entry(skipped_domain.erc20.clone())
+                .or_default()
+                .entry(skipped_domain.reason)
+                .or_default() += 1;
+        }
+        summary
+    }
+
+    pub fn to_message(&self) -> String {
+        let format_counts = |counts: &HashMap<SkipReason, usize>| -> String {
+            let mut counts: Vec<String> = counts
+                .iter()
+                .map(|(reason, count)| format!("{}: {}", reason, count))
+                .collect();
+            counts.sort();
+            counts.join(", ")
+        };
+
+        let mut message = format!(
+            "Domains that couldn't be renewed: {} ({})",
+            self.total,
+            format_counts(&self.by_reason)
+        );
+        for (auto_renew_contract, counts) in &self.by_contract {
+            message.push_str(&format!(
+                "\n- auto renew contract {}: {}",
+                auto_renew_contract,
+                format_counts(counts)
+            ));
+        }
+        for (erc20, counts) in &self.by_token {
+            message.push_str(&format!("\n- token {}: {}", erc20, format_counts(counts)));
+        }
+        message
+    }
+}
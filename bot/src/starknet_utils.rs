@@ -1,10 +1,10 @@
-use crate::{config::Config, models::TxResult};
+use crate::config::Config;
 use starknet::{
-    core::types::{
-        MaybePendingTransactionReceipt, PendingTransactionReceipt, TransactionExecutionStatus,
-        TransactionReceipt,
+    core::types::StarknetError,
+    providers::{
+        jsonrpc::HttpTransport, JsonRpcClient, MaybeUnknownErrorCode, ProviderError,
+        StarknetErrorWithMessage,
     },
-    providers::{jsonrpc::HttpTransport, JsonRpcClient, Provider},
 };
 use url::Url;
 
@@ -12,52 +12,13 @@ pub fn create_jsonrpc_client(conf: &Config) -> JsonRpcClient<HttpTransport> {
     JsonRpcClient::new(HttpTransport::new(Url::parse(&conf.rpc.rpc_url).unwrap()))
 }
 
-pub async fn check_pending_transactions(conf: &Config, tx_results: &mut Vec<TxResult>) {
-    let client = create_jsonrpc_client(conf);
-    for tx_result in tx_results.iter_mut() {
-        if tx_result.reverted.is_none() {
-            match client.get_transaction_receipt(tx_result.tx_hash).await {
-                Ok(receipt) => match receipt {
-                    MaybePendingTransactionReceipt::PendingReceipt(pending_receipt) => {
-                        if let PendingTransactionReceipt::Invoke(invocation) = pending_receipt {
-                            match invocation.execution_result.status() {
-                                TransactionExecutionStatus::Succeeded => {
-                                    tx_result.reverted = Some(false);
-                                }
-                                TransactionExecutionStatus::Reverted => {
-                                    tx_result.reverted = Some(true);
-                                    tx_result.revert_reason = invocation
-                                        .execution_result
-                                        .revert_reason()
-                                        .map(|s| s.to_owned());
-                                }
-                            }
-                        }
-                    }
-                    MaybePendingTransactionReceipt::Receipt(receipt) => {
-                        if let TransactionReceipt::Invoke(invocation) = receipt {
-                            match invocation.execution_result.status() {
-                                TransactionExecutionStatus::Succeeded => {
-                                    tx_result.reverted = Some(false);
-                                }
-                                TransactionExecutionStatus::Reverted => {
-                                    tx_result.reverted = Some(true);
-                                    tx_result.revert_reason = invocation
-                                        .execution_result
-                                        .revert_reason()
-                                        .map(|s| s.to_owned());
-                                }
-                            }
-                        }
-                    }
-                },
-                Err(e) => {
-                    eprintln!(
-                        "Error checking status for tx_hash {}: {}",
-                        tx_result.tx_hash, e
-                    );
-                }
-            }
-        }
-    }
+// Whether the node answered with the given starknet error code
+pub fn is_starknet_error<E>(error: &ProviderError<E>, expected: StarknetError) -> bool {
+    matches!(
+        error,
+        ProviderError::StarknetError(StarknetErrorWithMessage {
+            code: MaybeUnknownErrorCode::Known(code),
+            ..
+        }) if *code == expected
+    )
 }
@@ -6,7 +6,6 @@ use starknet::{
     macros::selector,
     providers::Provider,
 };
-use std::str::FromStr;
 
 use crate::{config::Config, starknet_utils::create_jsonrpc_client};
 
@@ -23,7 +22,7 @@ pub fn get_renewal_price_eth(domain: String) -> BigInt {
         .strip_suffix(".stark")
         .ok_or_else(|| anyhow::anyhow!("Invalid domain name: {:?}", domain))
         .unwrap();
-    let domain_len = domain_name.len();
+    let domain_len = domain_name.chars().count();
     match domain_len {
         1 => PRICE_DOMAIN_LEN_1.clone(),
         2 => PRICE_DOMAIN_LEN_2.clone(),
@@ -35,21 +34,25 @@ pub fn get_renewal_price_eth(domain: String) -> BigInt {
 
 #[derive(Deserialize, Debug)]
 pub struct QuoteQueryResult {
-    quote: String,
-    max_quote_validity: u64,
+    pub quote: String,
+    // timestamp until which the quote is accepted by the auto renew contract
+    pub max_quote_validity: u64,
 }
 
-pub async fn get_altcoin_quote(config: &Config, erc20: String) -> Result<BigInt> {
+pub async fn get_altcoin_quote(
+    config: &Config,
+    client: &reqwest::Client,
+    erc20: &str,
+) -> Result<QuoteQueryResult> {
     // Get quote from starknetid api
     let url = format!(
         "{}/get_altcoin_quote?erc20_addr={}",
         config.server.starknetid_api, erc20
     );
-    let client = reqwest::Client::new();
     match client.get(&url).send().await {
         Ok(response) => match response.text().await {
             Ok(text) => match serde_json::from_str::<QuoteQueryResult>(&text) {
-                Ok(results) => Ok(BigInt::from_str(&results.quote).unwrap()),
+                Ok(results) => Ok(results),
                 Err(err) => Err(anyhow!("Error parsing response: {:?}", err)),
             },
             Err(err) => Err(anyhow!("Error fetching response: {:?}", err)),
@@ -58,37 +61,55 @@ pub async fn get_altcoin_quote(config: &Config, erc20: String) -> Result<BigInt>
     }
 }
 
-pub async fn get_balances(
+// For each (renewer, erc20, auto renew contract) returns the balance of the renewer followed by
+// its allowance to the auto renew contract, both as [2, low, high]
+pub async fn get_balances_and_allowances(
     config: &Config,
-    mut renewer_and_erc20: Vec<(String, String)>,
-) -> Vec<FieldElement> {
+    mut renewers: Vec<(String, String, FieldElement)>,
+) -> Result<Vec<FieldElement>> {
     let mut balances: Vec<FieldElement> = vec![];
 
-    while !renewer_and_erc20.is_empty() {
-        let size = renewer_and_erc20.len().min(2500);
-        let batch = renewer_and_erc20.drain(0..size).collect();
-        let batch_balances = fetch_users_balances(config, batch).await;
+    while !renewers.is_empty() {
+        // 2 calls per renewer
+        let size = renewers.len().min(1250);
+        let batch = renewers.drain(0..size).collect();
+        let batch_balances = fetch_balances_and_allowances(config, batch).await?;
         // we skip the first 2 elements, the first one is the index of the call, the 2nd the length of the results
-        balances.extend(batch_balances.into_iter().skip(2));
+        let batch_balances: Vec<FieldElement> = batch_balances.into_iter().skip(2).collect();
+        // a missing value would shift the balances of all the following renewers
+        if batch_balances.len() != 3 * 2 * size {
+            return Err(anyhow!(
+                "Unexpected multicall result length: {} for {} renewers",
+                batch_balances.len(),
+                size
+            ));
+        }
+        balances.extend(batch_balances);
     }
 
-    balances
+    Ok(balances)
 }
 
-pub async fn fetch_users_balances(
+pub async fn fetch_balances_and_allowances(
     config: &Config,
-    renewers_and_erc20: Vec<(String, String)>,
-) -> Vec<FieldElement> {
-    let mut calls: Vec<FieldElement> = vec![FieldElement::from(renewers_and_erc20.len())];
-    for (renewer, erc20) in &renewers_and_erc20 {
+    renewers: Vec<(String, String, FieldElement)>,
+) -> Result<Vec<FieldElement>> {
+    let mut calls: Vec<FieldElement> = vec![FieldElement::from(renewers.len() * 2)];
+    for (renewer, erc20, auto_renew_contract) in &renewers {
         calls.push(FieldElement::from_hex_be(erc20).unwrap());
         calls.push(selector!("balanceOf"));
         calls.push(FieldElement::ONE);
         calls.push(FieldElement::from_hex_be(renewer).unwrap());
+
+        calls.push(FieldElement::from_hex_be(erc20).unwrap());
+        calls.push(selector!("allowance"));
+        calls.push(FieldElement::TWO);
+        calls.push(FieldElement::from_hex_be(renewer).unwrap());
+        calls.push(*auto_renew_contract);
     }
 
     let provider = create_jsonrpc_client(config);
-    let call_result = provider
+    provider
         .call(
             FunctionCall {
                 contract_address: config.contract.multicall,
@@ -97,13 +118,46 @@ pub async fn fetch_users_balances(
             },
             BlockId::Tag(BlockTag::Latest),
         )
-        .await;
+        .await
+        .map_err(|e| anyhow!("Error while fetching balances: {:?}", e))
+}
 
-    match call_result {
-        Ok(result) => result,
-        Err(err) => {
-            println!("Error while fetching balances: {:?}", err);
-            vec![]
+// On-chain expiry of root domains read from the naming contract, in the order of domains
+pub async fn get_domains_expiry(config: &Config, domains: &[FieldElement]) -> Result<Vec<i64>> {
+    let provider = create_jsonrpc_client(config);
+    let mut expiries: Vec<i64> = Vec::with_capacity(domains.len());
+    for chunk in domains.chunks(2500) {
+        let mut calls: Vec<FieldElement> = vec![FieldElement::from(chunk.len())];
+        for domain in chunk {
+            calls.push(config.contract.naming);
+            calls.push(selector!("domain_to_expiry"));
+            calls.push(FieldElement::TWO);
+            // the domain is passed as a span of its labels
+            calls.push(FieldElement::ONE);
+            calls.push(*domain);
+        }
+        let result = provider
+            .call(
+                FunctionCall {
+                    contract_address: config.contract.multicall,
+                    entry_point_selector: selector!("aggregate"),
+                    calldata: calls,
+                },
+                BlockId::Tag(BlockTag::Latest),
+            )
+            .await
+            .map_err(|e| anyhow!("Error while fetching domains expiry: {:?}", e))?;
+        // we skip the block number and the length of the results, then each result is [1, expiry]
+        let mut values = result.into_iter().skip(2);
+        for _ in chunk {
+            values.next();
+            let expiry = values
+                .next()
+                .ok_or_else(|| anyhow!("Missing expiry in multicall result"))?;
+            expiries.push(
+                u64::try_from(expiry).map_err(|_| anyhow!("Invalid expiry {}", expiry))? as i64,
+            );
         }
     }
+    Ok(expiries)
 }
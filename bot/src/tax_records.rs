@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, fs};
+
+use anyhow::{anyhow, Result};
+use bigdecimal::BigDecimal;
+use bson::doc;
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::FieldElement;
+
+use crate::{
+    config::{ExportFormat, TaxExport},
+    models::{AggregateResults, AppState},
+    utils::{decode_domain, to_hex},
+};
+
+// Tax collected on a domain renewed by a successful batch_renew transaction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaxRecord {
+    pub tx_hash: String,
+    pub domain: String,
+    pub renewer: String,
+    pub auto_renew_contract: String,
+    pub erc20: String,
+    pub tax_state: Option<String>,
+    // amounts in the smallest unit of the token
+    pub domain_price: String,
+    pub tax_price: String,
+    pub renewed_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TaxTotal {
+    pub tax_state: String,
+    pub erc20: String,
+    pub domains: usize,
+    pub base_amount: String,
+    pub tax_amount: String,
+}
+
+fn tax_records(state: &AppState) -> mongodb::Collection<TaxRecord> {
+    state.db.collection::<TaxRecord>("auto_renew_tax_records")
+}
+
+pub async fn record_batch(
+    state: &AppState,
+    tx_hash: FieldElement,
+    auto_renew_contract: FieldElement,
+    erc20: String,
+    batch: &AggregateResults,
+) -> Result<()> {
+    let renewed_at = Utc::now().timestamp();
+    let records: Vec<TaxRecord> = (0..batch.len())
+        .map(|i| TaxRecord {
+            tx_hash: to_hex(tx_hash),
+            domain: decode_domain(batch.domains[i]),
+            renewer: to_hex(batch.renewers[i]),
+            auto_renew_contract: to_hex(auto_renew_contract),
+            erc20: erc20.clone(),
+            tax_state: batch.tax_states[i].clone(),
+            domain_price: batch.domain_prices[i].to_string(),
+            tax_price: batch.tax_prices[i].to_string(),
+            renewed_at,
+        })
+        .collect();
+    if !records.is_empty() {
+        tax_records(state).insert_many(records, None).await?;
+    }
+    Ok(())
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|e| anyhow!("Invalid date {}: {}", date, e))
+}
+
+// Totals per tax state and token of the domains renewed between from and to (inclusive)
+pub async fn export(state: &AppState, tax_export: &TaxExport) -> Result<()> {
+    let from = match &tax_export.from {
+        Some(from) => parse_date(from)?,
+        None => NaiveDate::from_ymd_opt(1970, 1, 1).unwrap(),
+    };
+    let to = match &tax_export.to {
+        Some(to) => parse_date(to)?,
+        None => Utc::now().date_naive(),
+    };
+    let start = Utc
+        .from_utc_datetime(&from.and_hms_opt(0, 0, 0).unwrap())
+        .timestamp();
+    let end = Utc
+        .from_utc_datetime(&(to + Duration::days(1)).and_hms_opt(0, 0, 0).unwrap())
+        .timestamp();
+
+    let records: Vec<TaxRecord> = tax_records(state)
+        .find(doc! { "renewed_at": { "$gte": start, "$lt": end } }, None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut totals: BTreeMap<(String, String), (usize, BigDecimal, BigDecimal)> = BTreeMap::new();
+    for record in records {
+        let tax_state = record.tax_state.unwrap_or_else(|| "none".to_string());
+        let total = totals
+            .entry((tax_state, record.erc20))
+            .or_insert_with(|| (0, BigDecimal::from(0), BigDecimal::from(0)));
+        total.0 += 1;
+        total.1 += record.domain_price.parse::<BigDecimal>()?;
+        total.2 += record.tax_price.parse::<BigDecimal>()?;
+    }
+    let totals: Vec<TaxTotal> = totals
+        .into_iter()
+        .map(
+            |((tax_state, erc20), (domains, base_amount, tax_amount))| TaxTotal {
+                tax_state,
+                erc20,
+                domains,
+                base_amount: base_amount.to_string(),
+                tax_amount: tax_amount.to_string(),
+            },
+        )
+        .collect();
+
+    let output = match tax_export.format {
+        ExportFormat::Csv => {
+            let mut csv = "tax_state,erc20,domains,base_amount,tax_amount\n".to_string();
+            for total in &totals {
+                csv.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    total.tax_state,
+                    total.erc20,
+                    total.domains,
+                    total.base_amount,
+                    total.tax_amount
+                ));
+            }
+            csv
+        }
+        ExportFormat::Json => serde_json::to_string_pretty(&serde_json::json!({
+            "from": from.to_string(),
+            "to": to.to_string(),
+            "totals": totals,
+        }))?,
+    };
+    match &tax_export.output {
+        Some(path) => fs::write(path, output)?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use bigdecimal::num_bigint::BigInt;
+use starknet::{
+    core::{
+        types::{BlockId, BlockTag, FieldElement, FunctionCall},
+        utils::parse_cairo_short_string,
+    },
+    macros::selector,
+    providers::{jsonrpc::HttpTransport, JsonRpcClient, Provider},
+};
+
+use crate::{config::Config, starknet_utils::create_jsonrpc_client};
+
+// Decimals of ETH, in which renewal prices are computed
+const ETH_DECIMALS: u32 = 18;
+// Altcoin quotes of the starknet.id api are the price of 1 ETH in the token with 18 decimals
+pub const QUOTE_DECIMALS: u32 = 18;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub symbol: String,
+    pub decimals: u32,
+}
+
+// Metadata of the ERC20 of every renewer, taken from the config or read from the token contract
+pub async fn load_tokens(config: &Config) -> Result<HashMap<FieldElement, Token>> {
+    let provider = create_jsonrpc_client(config);
+    let mut tokens = HashMap::new();
+    for (name, renewer) in &config.renewers {
+        let decimals = match renewer.decimals {
+            Some(decimals) => decimals,
+            None => {
+                let result = call_token(&provider, renewer.address, selector!("decimals")).await?;
+                u32::try_from(result).map_err(|_| anyhow!("Invalid decimals for token {}", name))?
+            }
+        };
+        let symbol = match &renewer.symbol {
+            Some(symbol) => symbol.clone(),
+            None => {
+                let result = call_token(&provider, renewer.address, selector!("symbol")).await?;
+                parse_cairo_short_string(&result)
+                    .map_err(|e| anyhow!("Invalid symbol for token {}: {}", name, e))?
+            }
+        };
+        tokens.insert(renewer.address, Token { symbol, decimals });
+    }
+    Ok(tokens)
+}
+
+async fn call_token(
+    provider: &JsonRpcClient<HttpTransport>,
+    erc20: FieldElement,
+    selector: FieldElement,
+) -> Result<FieldElement> {
+    provider
+        .call(
+            FunctionCall {
+                contract_address: erc20,
+                entry_point_selector: selector,
+                calldata: vec![],
+            },
+            BlockId::Tag(BlockTag::Latest),
+        )
+        .await
+        .map_err(|e| anyhow!("Error while calling token 0x{:x}: {}", erc20, e))?
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("Empty result from token 0x{:x}", erc20))
+}
+
+// Price in the smallest unit of the token of a price in wei, rounded down
+pub fn convert_eth_price(price_eth: &BigInt, quote: &BigInt, token_decimals: u32) -> BigInt {
+    let numerator = price_eth * quote * BigInt::from(10).pow(token_decimals);
+    let denominator = BigInt::from(10).pow(ETH_DECIMALS + QUOTE_DECIMALS);
+    numerator / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn amount(value: &str) -> BigInt {
+        BigInt::from_str(value).unwrap()
+    }
+
+    // 0.0028 ETH renewal
+    const PRICE_ETH: &str = "2800000000000000";
+
+    #[test]
+    fn eth_is_priced_one_to_one() {
+        let quote = amount("1000000000000000000");
+        assert_eq!(
+            convert_eth_price(&amount(PRICE_ETH), &quote, 18),
+            amount(PRICE_ETH)
+        );
+    }
+
+    #[test]
+    fn strk_keeps_18_decimals() {
+        // 1 ETH = 1500.5 STRK
+        let quote = amount("1500500000000000000000");
+        assert_eq!(
+            convert_eth_price(&amount(PRICE_ETH), &quote, 18),
+            amount("4201400000000000000")
+        );
+    }
+
+    #[test]
+    fn usdc_is_scaled_to_6_decimals() {
+        // 1 ETH = 3000 USDC, 0.0028 ETH = 8.4 USDC
+        let quote = amount("3000000000000000000000");
+        assert_eq!(
+            convert_eth_price(&amount(PRICE_ETH), &quote, 6),
+            amount("8400000")
+        );
+    }
+
+    #[test]
+    fn usdc_price_is_rounded_down() {
+        // 1 ETH = 3333.333333333333333333 USDC, 0.0028 ETH = 9.333333 USDC
+        let quote = amount("3333333333333333333333");
+        assert_eq!(
+            convert_eth_price(&amount(PRICE_ETH), &quote, 6),
+            amount("9333333")
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::Utc;
+use serde::Serialize;
+use starknet::{
+    core::types::{
+        ExecutionResult, MaybePendingTransactionReceipt, PendingTransactionReceipt, StarknetError,
+        TransactionFinalityStatus, TransactionReceipt,
+    },
+    providers::Provider,
+};
+use tokio::time::{sleep, Duration as TokioDuration};
+
+use crate::{
+    config::Config,
+    logger::Logger,
+    models::TxResult,
+    starknet_utils::{create_jsonrpc_client, is_starknet_error},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    Received,
+    NotFound,
+    Pending,
+    AcceptedOnL2,
+    AcceptedOnL1,
+    // the transaction was never included, its nonce wasn't consumed
+    Rejected,
+}
+
+impl TxResult {
+    pub fn is_final(&self, wait_for_l1: bool) -> bool {
+        match self.status {
+            TxStatus::AcceptedOnL1 | TxStatus::Rejected => true,
+            TxStatus::AcceptedOnL2 => !wait_for_l1,
+            _ => false,
+        }
+    }
+}
+
+fn set_execution_result(tx_result: &mut TxResult, execution_result: &ExecutionResult) {
+    match execution_result {
+        ExecutionResult::Succeeded => tx_result.reverted = Some(false),
+        ExecutionResult::Reverted { reason } => {
+            tx_result.reverted = Some(true);
+            tx_result.revert_reason = Some(reason.to_owned());
+        }
+    }
+}
+
+// Fetch the receipts of the transactions that aren't final yet. A transaction still unknown to
+// the node after not_found_timeout seconds is considered rejected.
+pub async fn check_transactions(conf: &Config, tx_results: &mut [TxResult]) {
+    let client = create_jsonrpc_client(conf);
+    let now = Utc::now().timestamp();
+    for tx_result in tx_results.iter_mut() {
+        if tx_result.is_final(conf.transactions.wait_for_l1) {
+            continue;
+        }
+        match client.get_transaction_receipt(tx_result.tx_hash).await {
+            Ok(MaybePendingTransactionReceipt::PendingReceipt(
+                PendingTransactionReceipt::Invoke(receipt),
+            )) => {
+                tx_result.status = TxStatus::Pending;
+                set_execution_result(tx_result, &receipt.execution_result);
+            }
+            Ok(MaybePendingTransactionReceipt::Receipt(TransactionReceipt::Invoke(receipt))) => {
+                tx_result.status = match receipt.finality_status {
+                    TransactionFinalityStatus::AcceptedOnL2 => TxStatus::AcceptedOnL2,
+                    TransactionFinalityStatus::AcceptedOnL1 => TxStatus::AcceptedOnL1,
+                };
+                set_execution_result(tx_result, &receipt.execution_result);
+            }
+            Ok(_) => {}
+            Err(e) if is_starknet_error(&e, StarknetError::TransactionHashNotFound) => {
+                if now - tx_result.sent_at > conf.transactions.not_found_timeout as i64 {
+                    tx_result.status = TxStatus::Rejected;
+                    tx_result.reverted = Some(true);
+                    tx_result.revert_reason = Some(format!(
+                        "Transaction not found after {} seconds",
+                        conf.transactions.not_found_timeout
+                    ));
+                } else {
+                    tx_result.status = TxStatus::NotFound;
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Error checking status for tx_hash {}: {}",
+                    tx_result.tx_hash, e
+                );
+            }
+        }
+    }
+}
+
+// Poll the receipts with an exponential backoff until every transaction is final or
+// finality_timeout is reached
+pub async fn wait_for_finality(conf: &Config, tx_results: &mut [TxResult], logger: &Logger) {
+    let started_at = Utc::now().timestamp();
+    let mut interval = conf.transactions.poll_interval;
+    loop {
+        check_transactions(conf, tx_results).await;
+        let pending = tx_results
+            .iter()
+            .filter(|tx| !tx.is_final(conf.transactions.wait_for_l1))
+            .count();
+        if pending == 0 {
+            return;
+        }
+        if Utc::now().timestamp() - started_at > conf.transactions.finality_timeout as i64 {
+            logger.warning(format!(
+                "{} transactions are still not final after {} seconds",
+                pending, conf.transactions.finality_timeout
+            ));
+            return;
+        }
+        sleep(TokioDuration::from_secs(interval)).await;
+        interval = (interval * 2).min(conf.transactions.max_poll_interval);
+    }
+}
@@ -1,6 +1,7 @@
 use bigdecimal::{num_bigint::BigInt, BigDecimal};
 use num_integer::Integer;
 use starknet::core::types::FieldElement;
+use starknet_id::decode;
 use std::fmt::Write;
 
 lazy_static::lazy_static! {
@@ -45,3 +46,7 @@ pub fn to_hex(felt: FieldElement) -> String {
     }
     result
 }
+
+pub fn decode_domain(domain: FieldElement) -> String {
+    format!("{}.stark", decode(domain))
+}